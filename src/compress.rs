@@ -0,0 +1,191 @@
+use std::io::{Read, Write};
+use crate::{copy_bytes, read_full};
+
+pub const FRAME_SIZE: usize = 1_048_576; //1MB, compressed chunk bytes go in frames of up to this many file bytes
+const FRAME_HEADER_LEN: usize = 9; //u8 compression, u32 file bytes, u32 payload bytes
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None = 0,
+    Zstd = 1,
+    Lz4 = 2,
+}
+impl Compression {
+    pub fn from_u8(value: u8) -> Option<Compression> {
+        match value {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "none" => Some(Compression::None),
+            "zstd" => Some(Compression::Zstd),
+            "lz4" => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+        }
+    }
+}
+
+fn compress_frame(raw:&[u8], compression:Compression, level:i32) -> Result<Vec<u8>, std::io::Error> {
+	match compression {
+		Compression::None => Ok(raw.to_vec()),
+		Compression::Zstd => zstd::bulk::compress(raw, level),
+		Compression::Lz4 => Ok(lz4_flex::block::compress(raw)),
+	}
+}
+
+fn decompress_frame(payload:&[u8], compression:Compression, raw_len:usize) -> Result<Vec<u8>, std::io::Error> {
+	let raw = match compression {
+		Compression::None => payload.to_vec(),
+		Compression::Zstd => zstd::bulk::decompress(payload, raw_len)?,
+		Compression::Lz4 => lz4_flex::block::decompress(payload, raw_len).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?,
+	};
+	if raw.len() != raw_len {
+		Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Frame holds {} bytes, expected {}", raw.len(), raw_len)))?
+	}
+	Ok(raw)
+}
+
+pub fn send_bytes(reader:&mut impl Read, writer:&mut impl Write, len:u64, compression:Compression, level:i32) -> Result<u64, std::io::Error> {
+	//copies up to len bytes like copy_bytes. compressed, they go as frames of [u8 compression][u32 file bytes][u32 payload bytes][payload],
+	//a frame that does not get smaller is sent as it is. returns the file bytes sent
+	if compression == Compression::None {
+		return copy_bytes(reader, writer, len);
+	}
+	let mut raw = vec![0u8; len.min(FRAME_SIZE as u64) as usize];
+	let mut sent: u64 = 0;
+	while sent < len {
+		let max_read = (len - sent).min(raw.len() as u64) as usize;
+		let nbytes = read_full(reader, &mut raw[..max_read])?;
+		if nbytes == 0 {
+			break;
+		}
+		let compressed = compress_frame(&raw[..nbytes], compression, level)?;
+		let (frame_compression, payload) = if compressed.len() < nbytes {(compression, compressed.as_slice())} else {(Compression::None, &raw[..nbytes])};
+		let mut header = [0u8; FRAME_HEADER_LEN];
+		header[0] = frame_compression.to_u8();
+		header[1..5].copy_from_slice(&(nbytes as u32).to_le_bytes());
+		header[5..9].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+		writer.write_all(&header)?;
+		writer.write_all(payload)?;
+		sent += nbytes as u64;
+		if nbytes < max_read {
+			break;
+		}
+	}
+	Ok(sent)
+}
+
+pub fn receive_bytes(reader:&mut impl Read, writer:&mut impl Write, compression:Compression) -> Result<u64, std::io::Error> {
+	//writes out what send_bytes sent until the reader ends, which may only be between frames. returns the file bytes written
+	if compression == Compression::None {
+		return copy_bytes(reader, writer, u64::MAX);
+	}
+	let mut payload: Vec<u8> = Vec::new();
+	let mut written: u64 = 0;
+	loop {
+		let mut header = [0u8; FRAME_HEADER_LEN];
+		let nbytes = read_full(reader, &mut header)?;
+		if nbytes == 0 {
+			break;
+		}
+		if nbytes < FRAME_HEADER_LEN {
+			Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed inside a frame header"))?
+		}
+		let frame_compression = Compression::from_u8(header[0]).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unknown frame compression: {}", header[0])))?;
+		let raw_len = u32::from_le_bytes(header[1..5].try_into().expect("frame header slice is not 4 bytes")) as usize;
+		let payload_len = u32::from_le_bytes(header[5..9].try_into().expect("frame header slice is not 4 bytes")) as usize;
+		if raw_len > FRAME_SIZE || payload_len > FRAME_SIZE {
+			Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Frame of {} bytes ({} sent) is larger than {}", raw_len, payload_len, FRAME_SIZE)))?
+		}
+		payload.resize(payload_len, 0);
+		reader.read_exact(&mut payload)?;
+		writer.write_all(&decompress_frame(&payload, frame_compression, raw_len)?)?;
+		written += raw_len as u64;
+	}
+	Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{assert_round_trip, random_bytes};
+
+    fn text_bytes(len: usize) -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog. ".iter().copied().cycle().take(len).collect()
+    }
+
+    fn round_trip(raw: &[u8], compression: Compression) -> Vec<u8> {
+        //returns what went over the connection
+        let mut sent: Vec<u8> = Vec::new();
+        assert_eq!(send_bytes(&mut &raw[..], &mut sent, raw.len() as u64, compression, 3).unwrap(), raw.len() as u64);
+        let mut received: Vec<u8> = Vec::new();
+        let written = receive_bytes(&mut &sent[..], &mut received, compression);
+        assert_round_trip(raw, written, &received, compression.as_str());
+        sent
+    }
+
+    #[test]
+    fn test_round_trip_each_compression() {
+        //more than one frame, the last one short
+        let raw = text_bytes(FRAME_SIZE * 2 + 12_345);
+        assert_eq!(round_trip(&raw, Compression::None).len(), raw.len());
+        assert!(round_trip(&raw, Compression::Zstd).len() < raw.len() / 10);
+        assert!(round_trip(&raw, Compression::Lz4).len() < raw.len() / 10);
+    }
+
+    #[test]
+    fn test_round_trip_empty() {
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+            assert!(round_trip(&[], compression).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_incompressible_frame_sent_raw() {
+        let raw = random_bytes(100_000, 42);
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            let sent = round_trip(&raw, compression);
+            assert_eq!(sent.len(), FRAME_HEADER_LEN + raw.len());
+            assert_eq!(sent[0], Compression::None.to_u8());
+            assert!(sent[FRAME_HEADER_LEN..] == raw[..]);
+        }
+    }
+
+    #[test]
+    fn test_send_stops_at_len() {
+        let raw = text_bytes(50_000);
+        let mut sent: Vec<u8> = Vec::new();
+        assert_eq!(send_bytes(&mut &raw[..], &mut sent, 20_000, Compression::Zstd, 3).unwrap(), 20_000);
+        let mut received: Vec<u8> = Vec::new();
+        receive_bytes(&mut &sent[..], &mut received, Compression::Zstd).unwrap();
+        assert!(received == raw[..20_000]);
+    }
+
+    #[test]
+    fn test_receive_truncated_frame() {
+        let raw = text_bytes(50_000);
+        let mut sent: Vec<u8> = Vec::new();
+        send_bytes(&mut &raw[..], &mut sent, raw.len() as u64, Compression::Lz4, 0).unwrap();
+        for cut in [FRAME_HEADER_LEN - 1, sent.len() - 1] {
+            let result = receive_bytes(&mut &sent[..cut], &mut Vec::new(), Compression::Lz4);
+            assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use crc_fast::{CrcAlgorithm::Crc64Nvme, Digest};
+use helper_lib::paths::format_bytes;
+use log::*;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, Write};
+use std::path::Path;
+use crate::{BlockSignature, DigestReader, FileCopyStep, SIGNATURE, STREAM_BUFFER_SIZE, TransferOptions, TransferStats, UploadClientDelta, UploadClientSignatures, UploadServerSignatures, UploadServerTransfer, connect, copy_bytes, exchange_with_retries, file_len_mtime, read_full, read_response, request_error, with_retries};
+
+pub const MIN_BLOCK_SIZE: u64 = 2_048;
+pub const MAX_BLOCKS: u64 = 200_000; //keeps the signatures of a huge file to a few MB, the block size grows instead
+const MAX_LITERAL: usize = 1_048_576; //unmatched bytes are sent in pieces of up to this many
+const READ_SIZE: usize = 262_144;
+const LITERAL: u8 = 0; //[u8 0][u32 len][len bytes]
+const COPY: u8 = 1; //[u8 1][u32 first block][u32 block count]
+
+#[derive(Clone, Copy, Debug)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+impl Rolling {
+    //the rsync weak checksum: a is the sum of the bytes, b the sum of those sums. both move along a byte at a time without going over the block again
+    fn new(block: &[u8]) -> Rolling {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for byte in block {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add(a);
+        }
+        Rolling {
+            a: a,
+            b: b,
+            len: block.len() as u32,
+        }
+    }
+
+    fn roll(&mut self, out_byte: u8, in_byte: u8) {
+        self.a = self.a.wrapping_sub(out_byte as u32).wrapping_add(in_byte as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out_byte as u32)).wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DeltaStats {
+    pub literal_bytes: u64,
+    pub matched_bytes: u64,
+}
+
+pub fn block_size(basis_len:u64) -> u32 {
+	//about the square root of the file length as rsync does, larger where that would make more than MAX_BLOCKS blocks
+	let size = ((basis_len as f64).sqrt() as u64).max(basis_len.div_ceil(MAX_BLOCKS)).max(MIN_BLOCK_SIZE);
+	size.min(u32::MAX as u64) as u32
+}
+
+pub fn block_signatures(basis:&Path, block_size:u32) -> Result<Vec<BlockSignature>, std::io::Error> {
+	let mut file = File::open(basis)?;
+	let mut block = vec![0u8; block_size.max(1) as usize];
+	let mut blocks: Vec<BlockSignature> = Vec::new();
+	loop {
+		let nbytes = read_full(&mut file, &mut block)?;
+		if nbytes == 0 {
+			break;
+		}
+		blocks.push(BlockSignature {
+			weak: Rolling::new(&block[..nbytes]).digest(),
+			strong: crc_fast::checksum(Crc64Nvme, &block[..nbytes]),
+		});
+		if nbytes < block.len() {
+			break;
+		}
+	}
+	Ok(blocks)
+}
+
+struct DeltaWriter<'a, W: Write> {
+    out: &'a mut W,
+    pending_copy: Option<(u32, u32)>, //consecutive blocks go as one COPY
+    stats: DeltaStats,
+}
+impl<W: Write> DeltaWriter<'_, W> {
+    fn literal(&mut self, bytes: &[u8]) -> Result<(), std::io::Error> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.flush_copy()?;
+        self.out.write_all(&[LITERAL])?;
+        self.out.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        self.out.write_all(bytes)?;
+        self.stats.literal_bytes += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn copy(&mut self, index: u32, len: u64) -> Result<(), std::io::Error> {
+        self.stats.matched_bytes += len;
+        if let Some((first, count)) = self.pending_copy.as_mut() && *first + *count == index {
+            *count += 1;
+            return Ok(());
+        }
+        self.flush_copy()?;
+        self.pending_copy = Some((index, 1));
+        Ok(())
+    }
+
+    fn flush_copy(&mut self) -> Result<(), std::io::Error> {
+        if let Some((first, count)) = self.pending_copy.take() {
+            self.out.write_all(&[COPY])?;
+            self.out.write_all(&first.to_le_bytes())?;
+            self.out.write_all(&count.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn find_block(by_weak:&HashMap<u32, Vec<u32>>, blocks:&[BlockSignature], weak:u32, window:&[u8]) -> Option<u32> {
+	let candidates = by_weak.get(&weak)?;
+	let strong = crc_fast::checksum(Crc64Nvme, window);
+	candidates.iter().copied().find(|index| blocks[*index as usize].strong == strong)
+}
+
+pub fn write_delta(src:&mut impl Read, blocks:&[BlockSignature], block_size:usize, basis_len:u64, out:&mut impl Write) -> Result<DeltaStats, std::io::Error> {
+	//src as LITERAL and COPY instructions against the basis file the blocks are of. only a window of src is held, never the whole file
+	let full_blocks = (basis_len / block_size as u64) as usize;
+	let mut by_weak: HashMap<u32, Vec<u32>> = HashMap::new();
+	for (index, block) in blocks.iter().enumerate().take(full_blocks) {
+		by_weak.entry(block.weak).or_default().push(index as u32);
+	}
+	let mut writer = DeltaWriter {
+		out: out,
+		pending_copy: None,
+		stats: DeltaStats::default(),
+	};
+	//data[literal_start..pos] is not matched yet, data[pos..pos+block_size] is the window being tried
+	let mut data: Vec<u8> = Vec::new();
+	let mut literal_start: usize = 0;
+	let mut pos: usize = 0;
+	let mut is_eof = false;
+	let mut rolling: Option<Rolling> = None;
+	loop {
+		if !is_eof && data.len() < pos + block_size + 1 {
+			data.drain(..literal_start);
+			pos -= literal_start;
+			literal_start = 0;
+			let filled = data.len();
+			let max_read = READ_SIZE.max(block_size + 1);
+			data.resize(filled + max_read, 0);
+			let nbytes = read_full(src, &mut data[filled..])?;
+			data.truncate(filled + nbytes);
+			is_eof = nbytes < max_read;
+			continue;
+		}
+		if data.len() - pos < block_size {
+			break;
+		}
+		let window = &data[pos..pos + block_size];
+		let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+		if let Some(index) = find_block(&by_weak, blocks, weak, window) {
+			writer.literal(&data[literal_start..pos])?;
+			writer.copy(index, block_size as u64)?;
+			pos += block_size;
+			literal_start = pos;
+			rolling = None;
+			continue;
+		}
+		//no block starts here, move the window along a byte
+		if pos + block_size < data.len() {
+			rolling.as_mut().expect("rolling checksum not started").roll(data[pos], data[pos + block_size]);
+		} else {
+			rolling = None;
+		}
+		pos += 1;
+		if pos - literal_start >= MAX_LITERAL {
+			writer.literal(&data[literal_start..pos])?;
+			literal_start = pos;
+		}
+	}
+	//what is left is shorter than a block, it can still be the basis file's short last block
+	let tail = &data[pos..];
+	let last_index = blocks.len().saturating_sub(1);
+	let is_last_block = !tail.is_empty() && blocks.len() > full_blocks && tail.len() as u64 == basis_len - (full_blocks * block_size) as u64
+		&& Rolling::new(tail).digest() == blocks[last_index].weak && crc_fast::checksum(Crc64Nvme, tail) == blocks[last_index].strong;
+	if is_last_block {
+		writer.literal(&data[literal_start..pos])?;
+		writer.copy(last_index as u32, tail.len() as u64)?;
+	} else {
+		writer.literal(&data[literal_start..])?;
+	}
+	writer.flush_copy()?;
+	Ok(writer.stats)
+}
+
+pub fn apply_delta(instructions:&mut impl Read, basis:&mut File, out:&mut impl Write, block_size:u64, basis_len:u64) -> Result<u64, std::io::Error> {
+	//rebuilds the file write_delta described into out, returns its length
+	let nblocks = basis_len.div_ceil(block_size.max(1));
+	let mut written: u64 = 0;
+	loop {
+		let mut kind = [0u8; 1];
+		if read_full(instructions, &mut kind)? == 0 {
+			break;
+		}
+		let mut fields = [0u8; 8];
+		match kind[0] {
+			LITERAL => {
+				instructions.read_exact(&mut fields[..4])?;
+				let len = u32::from_le_bytes(fields[..4].try_into().expect("literal length is not 4 bytes")) as u64;
+				if copy_bytes(&mut instructions.take(len), out, len)? != len {
+					Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed inside a literal"))?
+				}
+				written += len;
+			}
+			COPY => {
+				instructions.read_exact(&mut fields)?;
+				let first = u32::from_le_bytes(fields[..4].try_into().expect("block index is not 4 bytes")) as u64;
+				let count = u32::from_le_bytes(fields[4..].try_into().expect("block count is not 4 bytes")) as u64;
+				if count == 0 || first + count > nblocks {
+					Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Blocks {}..{} are not in the {} block file", first, first + count, nblocks)))?
+				}
+				let start = first * block_size;
+				let len = (count * block_size).min(basis_len - start);
+				basis.seek(std::io::SeekFrom::Start(start))?;
+				if copy_bytes(basis, out, len)? != len {
+					Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "file on server is shorter than its block signatures"))?
+				}
+				written += len;
+			}
+			other => {
+				Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unknown delta instruction: {}", other)))?
+			}
+		}
+	}
+	Ok(written)
+}
+
+pub fn upload_delta(address:&str, src:&Path, dest:&Path, basis_len:u64, src_len_mtime:(u64, u64), options:&TransferOptions, stats:&mut TransferStats) -> Result<Option<u64>, Box<dyn Error>> {
+/*
+Delta Upload, in place of sending chunks when the server already has a different version of the file:
+1. client: for this relative path, the signatures of your file in blocks of this size please
+   server: here is a weak and a strong checksum for each block, and the size and mtime of the file they are of
+2. client: for this relative path, here is my file as bytes you do not have and references to blocks you do
+   server: rebuilds it into the partial file from those and its own file, and here is the length of the result
+The caller finishes with End as for any upload, its crc check covers the rebuilt file.
+Returns the crc of src as it was read for the instructions, or None if src changed while being sent, leaving the caller to start again.
+*/
+
+	let block_size = block_size(basis_len);
+	let upload_client_signatures = UploadClientSignatures {
+		serverside_path: dest.to_string_lossy().to_string(),
+		block_size: block_size,
+	};
+	let serialized = wincode::serialize(&upload_client_signatures)?;
+	let step:FileCopyStep = FileCopyStep::Signatures;
+	let package = [SIGNATURE.to_vec(), vec![1u8], vec![step.to_u8()], serialized].concat();
+	let upload_server_signatures: UploadServerSignatures;
+	{
+		let buffer_from_server = exchange_with_retries(address, &package, options, stats).map_err(|e| request_error(address, e))?;
+		upload_server_signatures = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to UploadServerSignatures");
+	}
+	if let Some(errmsg) = upload_server_signatures.error_msg {
+		error!("{errmsg}");
+		return Err(errmsg)?;
+	}
+	debug!("{} block signatures of {} bytes", upload_server_signatures.blocks.len(), block_size);
+
+	let upload_client_delta = UploadClientDelta {
+		serverside_path: dest.to_string_lossy().to_string(),
+		block_size: block_size,
+		basis_len: upload_server_signatures.filelen,
+		basis_mtime: upload_server_signatures.mtime,
+	};
+	let serialized = wincode::serialize(&upload_client_delta)?;
+	let header_len: u64 = serialized.len() as u64;
+	let step:FileCopyStep = FileCopyStep::Delta;
+	let head = [SIGNATURE.to_vec(), vec![1u8], vec![step.to_u8()], header_len.to_le_bytes().to_vec(), serialized].concat();
+	//the instructions are worked out while they are sent, a failed connection works them out again
+	let (buffer_from_server, delta_stats, file_crc) = with_retries(address, options, stats, || {
+		let mut reader = DigestReader {
+			inner: File::open(src)?,
+			digest: Digest::new(Crc64Nvme),
+		};
+		let mut stream = connect(address, options)?;
+		stream.write_all(&head)?;
+		let delta_stats = {
+			let mut writer = BufWriter::with_capacity(STREAM_BUFFER_SIZE, &stream);
+			let delta_stats = write_delta(&mut reader, &upload_server_signatures.blocks, block_size as usize, upload_server_signatures.filelen, &mut writer)?;
+			writer.flush()?;
+			delta_stats
+		};
+		Ok((read_response(stream)?, delta_stats, reader.digest.finalize()))
+	}).map_err(|e| request_error(address, e))?;
+	if file_len_mtime(src)? != src_len_mtime {
+		return Ok(None);
+	}
+	let upload_server_transfer: UploadServerTransfer = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to UploadServerTransfer");
+	if let Some(errmsg) = upload_server_transfer.error_msg {
+		error!("{errmsg}");
+		return Err(errmsg)?;
+	}
+	if upload_server_transfer.filelen != src_len_mtime.0 {
+		Err(format!("File rebuilt on server is {} bytes, expected {}", upload_server_transfer.filelen, src_len_mtime.0))?
+	}
+	stats.bytes_transferred += delta_stats.literal_bytes;
+	info!("Delta upload of {}: {} sent, {} matched on server", src.to_string_lossy(), format_bytes(delta_stats.literal_bytes), format_bytes(delta_stats.matched_bytes));
+	Ok(Some(file_crc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{assert_round_trip, random_bytes, temp_path};
+    use std::fs;
+
+    fn round_trip(basis: &[u8], src: &[u8], block_size: u32) -> DeltaStats {
+        //src as a delta against basis and rebuilt from it, which has to give src back
+        let basis_path = temp_path("tfc_delta_test_", "");
+        fs::write(&basis_path, basis).unwrap();
+        let blocks = block_signatures(&basis_path, block_size).unwrap();
+        let mut instructions: Vec<u8> = Vec::new();
+        let delta_stats = write_delta(&mut &src[..], &blocks, block_size as usize, basis.len() as u64, &mut instructions).unwrap();
+        let mut rebuilt: Vec<u8> = Vec::new();
+        let written = apply_delta(&mut &instructions[..], &mut File::open(&basis_path).unwrap(), &mut rebuilt, block_size as u64, basis.len() as u64);
+        fs::remove_file(&basis_path).unwrap();
+        assert_round_trip(src, written, &rebuilt, "delta");
+        assert_eq!(delta_stats.literal_bytes + delta_stats.matched_bytes, src.len() as u64);
+        delta_stats
+    }
+
+    #[test]
+    fn test_rolling_matches_new() {
+        let bytes = random_bytes(5_000, 1);
+        let mut rolling = Rolling::new(&bytes[..2_048]);
+        for pos in 0..bytes.len() - 2_048 {
+            rolling.roll(bytes[pos], bytes[pos + 2_048]);
+            assert_eq!(rolling.digest(), Rolling::new(&bytes[pos + 1..pos + 1 + 2_048]).digest());
+        }
+    }
+
+    #[test]
+    fn test_delta_identical() {
+        let basis = random_bytes(10_000, 2);
+        let delta_stats = round_trip(&basis, &basis, 2_048);
+        assert_eq!(delta_stats.literal_bytes, 0);
+    }
+
+    #[test]
+    fn test_delta_empty_basis() {
+        let src = random_bytes(10_000, 3);
+        let delta_stats = round_trip(&[], &src, 2_048);
+        assert_eq!(delta_stats.matched_bytes, 0);
+    }
+
+    #[test]
+    fn test_delta_empty_src() {
+        let basis = random_bytes(10_000, 4);
+        let delta_stats = round_trip(&basis, &[], 2_048);
+        assert_eq!(delta_stats.literal_bytes, 0);
+    }
+
+    #[test]
+    fn test_delta_appended_tail() {
+        //the basis's short last block is not at the end of src any more, so it goes as a literal with the tail
+        let basis = random_bytes(10_000, 5);
+        let src = [basis.clone(), random_bytes(3_000, 6)].concat();
+        let delta_stats = round_trip(&basis, &src, 2_048);
+        assert_eq!(delta_stats.matched_bytes, 4 * 2_048);
+    }
+
+    #[test]
+    fn test_delta_inserted_bytes() {
+        //blocks after the insert are still found, a byte at a time past it
+        let basis = random_bytes(20_480, 7);
+        let src = [&basis[..5_000], &random_bytes(100, 8)[..], &basis[5_000..]].concat();
+        let delta_stats = round_trip(&basis, &src, 2_048);
+        assert!(delta_stats.matched_bytes >= 8 * 2_048);
+    }
+
+    #[test]
+    fn test_apply_delta_rejects_unknown_block() {
+        let basis_path = temp_path("tfc_delta_test_", "");
+        fs::write(&basis_path, random_bytes(4_096, 9)).unwrap();
+        let instructions = [vec![COPY], 2u32.to_le_bytes().to_vec(), 1u32.to_le_bytes().to_vec()].concat();
+        let result = apply_delta(&mut &instructions[..], &mut File::open(&basis_path).unwrap(), &mut Vec::new(), 2_048, 4_096);
+        fs::remove_file(&basis_path).unwrap();
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+}
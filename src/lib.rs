@@ -1,59 +1,275 @@
-use crc_fast::{checksum_file, CrcAlgorithm::Crc64Nvme};
+//fields are written out as field: field and errors returned as return Err(...)?, throughout the crate
+#![allow(clippy::redundant_field_names, clippy::needless_return_with_question_mark)]
+
+use crc_fast::{checksum_file, CrcAlgorithm::Crc64Nvme, Digest};
 use helper_lib::{datetime::{systemtime_to_unixtimestamp, unixtimestamp_to_systemtime}, paths::format_bytes};
 use log::*;
+use std::collections::VecDeque;
 use std::error::Error;
 use std::fs::{self, File, FileTimes, OpenOptions};
 use std::io::{Read, Seek, Write};
-use std::net::{TcpStream};
-use std::path::{PathBuf};
-use std::time::{SystemTime};
+use socket2::{SockRef, TcpKeepalive};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use uuid::Uuid;
 use wincode::{SchemaWrite, SchemaRead};
+use crate::compress::{Compression, receive_bytes, send_bytes};
+use crate::delta::upload_delta;
+use crate::ranges::{download_ranges, partial_ranges, upload_ranges};
+
+pub mod compress;
+pub mod delta;
+pub mod ranges;
+pub mod sync;
+pub mod watch;
+#[cfg(test)]
+mod test_util;
 
 pub const SIGNATURE: [u8; 4] = [0x54, 0x46, 0x43, 0x31]; //tfc1
 // pub const DEFAULT_CHUNK_SIZE: usize = 1_048_576; //1MB
 // pub const DEFAULT_CHUNK_SIZE: usize = 3_048_576; //3MB // max size for wincode serialization = 4MB for heap allocated structures https://github.com/anza-xyz/wincode/blob/9f0ffa346d95c31b94486b7bfea724b73330c42f/wincode/src/len.rs#L46
 // pub const DEFAULT_CHUNK_SIZE: usize = 10_485_760; //10MB
-pub const DEFAULT_CHUNK_SIZE: usize = 104_857_600; //100MB
+// pub const DEFAULT_CHUNK_SIZE: usize = 104_857_600; //100MB
+pub const DEFAULT_CHUNK_SIZE: usize = 4_194_304; //4MB, the first chunk. the ones after it are sized from how fast it went
+pub const DEFAULT_MIN_CHUNK_SIZE: usize = 262_144; //256KB
+pub const DEFAULT_MAX_CHUNK_SIZE: usize = 104_857_600; //100MB
+pub const DEFAULT_CHUNK_TARGET: Duration = Duration::from_secs(2); //how long a chunk should take to send
+pub const DEFAULT_MAX_RESTARTS: u32 = 3;
+pub const DEFAULT_WINDOW: usize = 4;
+pub const DEFAULT_CONNECTIONS: usize = 1;
+pub const DEFAULT_RETRIES: u32 = 5;
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+pub const DEFAULT_RETRY_BACKOFF_MAX: Duration = Duration::from_secs(30);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(60); //read and write timeouts, on the client and the server
+pub const DEFAULT_HASH_CACHE_ENTRIES: usize = 1024; //file crcs the server remembers, a download of a remembered file is sent with sendfile
+pub const DEFAULT_MAX_CONNECTIONS: usize = 256; //connections the server handles at once, subscriptions included. more wait to be handled
+pub const KEEPALIVE_TIME: Duration = Duration::from_secs(30);
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15); //an idle subscription sends a heartbeat this often, well inside the read timeout
+pub const STREAM_BUFFER_SIZE: usize = 262_144; //256KB, file bytes pass between socket and file through a buffer this size whatever the chunk size
+pub const PARTIAL_SUFFIX: &str = ".tfcpart"; //unfinished transfers are written to .name.tfcpart beside the final file
+pub const RANGES_SUFFIX: &str = ".tfcranges"; //and a transfer over several connections records its byte ranges in .name.tfcranges
+
+#[derive(Clone, Debug)]
+pub struct TransferOptions {
+    pub chunk_size: usize, //size of the first chunk, and of every chunk when chunk_target is zero
+    pub min_chunk_size: usize,
+    pub max_chunk_size: usize,
+    pub chunk_target: Duration, //chunks are resized between min and max to take about this long each
+    pub compression: Compression, //chunks are sent compressed when the server agrees
+    pub compression_level: i32, //zstd level, 0 for its default
+    pub delta: bool, //an upload over a different version of the file on the server sends only what differs from it
+    pub window: usize, //chunk requests a download keeps in flight
+    pub connections: usize, //a file longer than chunk_size is split into this many byte ranges, each sent over its own connection
+    pub max_restarts: u32, //times a transfer starts over because the source file changed while it was being sent
+    pub retries: u32, //times a request is sent again after the connection to the server fails
+    pub retry_backoff: Duration, //wait before the first retry, doubled for each retry after it
+    pub retry_backoff_max: Duration,
+    pub connect_timeout: Duration, //zero for no timeout, as for read and write
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+}
+impl Default for TransferOptions {
+    fn default() -> Self {
+        TransferOptions {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            min_chunk_size: DEFAULT_MIN_CHUNK_SIZE,
+            max_chunk_size: DEFAULT_MAX_CHUNK_SIZE,
+            chunk_target: DEFAULT_CHUNK_TARGET,
+            compression: Compression::None,
+            compression_level: 0,
+            delta: false,
+            window: DEFAULT_WINDOW,
+            connections: DEFAULT_CONNECTIONS,
+            max_restarts: DEFAULT_MAX_RESTARTS,
+            retries: DEFAULT_RETRIES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            retry_backoff_max: DEFAULT_RETRY_BACKOFF_MAX,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_IO_TIMEOUT,
+            write_timeout: DEFAULT_IO_TIMEOUT,
+        }
+    }
+}
+
+type ChunkRequest = JoinHandle<Result<(DownloadServerTransfer, TcpStream, TransferStats), std::io::Error>>;
+
+#[derive(Debug)]
+pub struct TimeoutError {
+    pub address: String,
+    pub source: std::io::Error,
+}
+impl std::fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Timed out talking to {}: {}", self.address, self.source)
+    }
+}
+impl Error for TimeoutError {}
+
+#[derive(Clone, Debug, Default)]
+pub struct TransferStats {
+    pub bytes_transferred: u64,
+    pub retries: u32,
+    pub restarts: u32,
+}
+impl TransferStats {
+    pub fn add(&mut self, other: &TransferStats) {
+        self.bytes_transferred += other.bytes_transferred;
+        self.retries += other.retries;
+        self.restarts += other.restarts;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChunkSizer {
+    size: u64,
+    min_size: u64,
+    max_size: u64,
+    target: Duration,
+}
+impl ChunkSizer {
+    //picks each chunk's size from how long the ones before it took, so a slow link still shows progress and loses little to a retry, and a fast one gets big chunks
+    pub fn new(options: &TransferOptions) -> ChunkSizer {
+        let min_size = options.min_chunk_size.max(1) as u64;
+        let max_size = (options.max_chunk_size as u64).max(min_size);
+        let size = if options.chunk_target.is_zero() {options.chunk_size.max(1) as u64} else {(options.chunk_size as u64).clamp(min_size, max_size)};
+        ChunkSizer {
+            size: size,
+            min_size: min_size,
+            max_size: max_size,
+            target: options.chunk_target,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn record(&mut self, nbytes: u64, elapsed: Duration) {
+        //nbytes took elapsed, request and reply included. the size moves towards what would take the target at that rate, by at most half or double per chunk
+        if self.target.is_zero() || nbytes == 0 {
+            return;
+        }
+        let rate = nbytes as f64 / elapsed.as_secs_f64().max(0.001);
+        let ideal = (rate * self.target.as_secs_f64()) as u64;
+        let size = ideal.clamp(self.size / 2, self.size.saturating_mul(2)).clamp(self.min_size, self.max_size);
+        if size != self.size {
+            debug!("chunk of {} took {:.2}s ({}/s), next chunks {}", format_bytes(nbytes), elapsed.as_secs_f64(), format_bytes(rate as u64), format_bytes(size));
+        }
+        self.size = size;
+    }
+}
 
 #[derive(Clone, Debug, SchemaWrite, SchemaRead)]
 pub struct DownloadClientInitalise {
     pub serverside_path: String,
+	pub resume_from: u64, //length of the partial file held by the client, 0 for none
+	pub prefix_crc: u64, //crc of those bytes
+	pub ranges: Vec<UploadRange>, //or the ranges of a ranged partial file, with the crc of what each holds
 }
 #[derive(Clone, Debug, SchemaWrite, SchemaRead)]
 pub struct DownloadServerInitalise {
 	pub error_msg: Option<String>,
 	pub filelen: u64,
 	pub mtime: u64,
-	pub crc: u64,
+	pub crc: Option<u64>, //only when the server already knows it, otherwise it is worked out as the bytes are sent and comes with End
+	pub prefix_matches: bool, //the client's partial file is the start of this file
+	pub ranges_match: Vec<bool>, //for each of the client's ranges, whether what it holds is that part of this file
+	pub is_dir: bool,
+	pub dir_files: Vec<String>, //relative paths, '/' separated
+	pub dir_dirs: Vec<String>,
 }
 #[derive(Clone, Debug, SchemaWrite, SchemaRead)]
 pub struct DownloadClientTransfer {
     pub serverside_path: String,
     pub from_byte: u64,
     pub chunk_size: usize,
+    pub compression: u8, //Compression asked for, the reply says what was used
+    pub compression_level: i32,
 }
 #[derive(Clone, Debug, SchemaWrite, SchemaRead)]
 pub struct DownloadServerTransfer {
 	pub error_msg: Option<String>,
+	pub filelen: u64, //current size and mtime of the file, to detect it changing during the download
+	pub mtime: u64,
+	pub compression: u8, //how the bytes after this header are sent
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct DownloadClientEnd {
+    pub serverside_path: String,
+	pub filelen: u64, //size and mtime from DownloadServerInitalise, the crc is of that version of the file
+	pub mtime: u64,
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct DownloadServerEnd {
+	pub error_msg: Option<String>,
+	pub filelen: u64, //current size and mtime of the file, crc is 0 if they are not the ones asked for
+	pub mtime: u64,
+	pub crc: u64,
 }
 
 #[derive(Clone, Debug, SchemaWrite, SchemaRead)]
 pub struct UploadClientInitalise {
     pub serverside_path: String,
 	pub is_continue: bool,
+	pub ranges: u32, //above 1, the file is sent as this many byte ranges in parallel and the server preallocates the partial file
+	pub filelen: u64, //length of the file being sent, for preallocating
+	pub compression: u8, //Compression the client would like to send chunks with
+	pub delta: bool, //report a finished file on the server even when not continuing, as the basis for a delta upload
 }
 #[derive(Clone, Debug, SchemaWrite, SchemaRead)]
 pub struct UploadServerInitalise {
 	pub error_msg: Option<String>,
 	pub filelen: u64,
+	pub prefix_crc: u64, //crc of the filelen bytes already on the server
+	pub exists_complete: bool, //filelen and prefix_crc are of the finished file, not a partial one
+	pub ranges: Vec<UploadRange>, //progress of a ranged upload, empty otherwise
+	pub compression: u8, //what the server accepts, Compression::None if it does not know the one asked for
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct UploadRange {
+	pub start: u64,
+	pub end: u64,
+	pub done: u64, //bytes already written from start
+	pub crc: u64, //crc of those bytes
 }
 #[derive(Clone, Debug, SchemaWrite, SchemaRead)]
 pub struct UploadClientTransfer {
     pub serverside_path: String,
+	pub offset: u64, //where in the partial file the bytes go, so a resent chunk overwrites rather than appends
+	pub compression: u8, //how the bytes after the header are sent
 }
 #[derive(Clone, Debug, SchemaWrite, SchemaRead)]
 pub struct UploadServerTransfer {
 	pub error_msg: Option<String>,
+	pub filelen: u64, //length of the partial file after the write
+}
+
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct UploadClientSignatures {
+    pub serverside_path: String,
+	pub block_size: u32,
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct UploadServerSignatures {
+	pub error_msg: Option<String>,
+	pub filelen: u64, //size and mtime of the file the blocks are of, the Delta is only applied to that same file
+	pub mtime: u64,
+	pub blocks: Vec<BlockSignature>,
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct BlockSignature {
+	pub weak: u32, //rolling checksum, cheap to compute at every offset of the file being sent
+	pub strong: u64, //crc64, to confirm a weak match
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct UploadClientDelta {
+    pub serverside_path: String,
+	pub block_size: u32,
+	pub basis_len: u64, //filelen and mtime from UploadServerSignatures
+	pub basis_mtime: u64,
 }
 #[derive(Clone, Debug, SchemaWrite, SchemaRead)]
 pub struct UploadClientEnd {
@@ -68,10 +284,96 @@ pub struct UploadServerEnd {
 #[derive(Clone, Debug, SchemaWrite, SchemaRead)]
 pub struct DeleteClientInitalise {
     pub serverside_path: String,
+	pub recursive: bool,
+	pub confirm: bool, //required for a recursive delete of a non-empty directory
+	pub dry_run: bool,
 }
 #[derive(Clone, Debug, SchemaWrite, SchemaRead)]
 pub struct DeleteServerResponse {
 	pub error_msg: Option<String>,
+	pub removed: Vec<String>, //paths removed, or that would be removed for a dry run
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct MkdirClientInitalise {
+    pub serverside_path: String,
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct MkdirServerResponse {
+	pub error_msg: Option<String>,
+	pub created: bool, //false if the directory already existed
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct ListClientInitalise {
+    pub serverside_path: String,
+	pub recursive: bool,
+	pub max_depth: u32, //0 = no limit
+	pub include_crc: bool,
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct ListEntry {
+	pub path: String, //relative to the listed directory, '/' separated
+	pub entry_type: u8, //EntryType
+	pub size: u64,
+	pub mtime: u64,
+	pub crc: Option<u64>,
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct ListServerResponse {
+	pub error_msg: Option<String>,
+	pub entries: Vec<ListEntry>,
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct StatClientInitalise {
+    pub serverside_path: String,
+	pub include_hash: bool,
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct StatServerResponse {
+	pub error_msg: Option<String>,
+	pub exists: bool,
+	pub entry_type: u8, //EntryType, of the path itself (symlinks are not followed)
+	pub size: u64,
+	pub mtime: u64,
+	pub permissions: u32, //unix mode bits, or 0o444/0o666 from the readonly flag elsewhere
+	pub readonly: bool,
+	pub symlink_target: Option<String>,
+	pub crc: Option<u64>,
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct SubscribeClientInitalise {
+    pub serverside_path: String,
+	pub settle_ms: u64,
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct SubscribeServerEvent {
+	pub error_msg: Option<String>,
+	pub change_type: u8, //ChangeType
+	pub path: String, //relative to the subscribed directory, '/' separated
+	pub entry_type: u8, //EntryType
+	pub size: u64,
+	pub mtime: u64,
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct MoveClientInitalise {
+    pub serverside_from: String,
+    pub serverside_to: String,
+	pub overwrite_policy: u8, //OverwritePolicy
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct MoveServerResponse {
+	pub error_msg: Option<String>,
+	pub skipped: bool,
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct CopyClientInitalise {
+    pub serverside_from: String,
+    pub serverside_to: String,
+	pub overwrite_policy: u8, //OverwritePolicy
+}
+#[derive(Clone, Debug, SchemaWrite, SchemaRead)]
+pub struct CopyServerResponse {
+	pub error_msg: Option<String>,
+	pub skipped: bool,
 }
 
 
@@ -79,6 +381,8 @@ pub struct DeleteServerResponse {
 pub enum FileCopyStep {
     Initialise = 0,
     Transfer = 10,
+    Signatures = 20, //delta upload: block signatures of the file already on the server
+    Delta = 30, //delta upload: the file as literal bytes and references to those blocks
     End = 200,
 }
 impl FileCopyStep {
@@ -86,6 +390,8 @@ impl FileCopyStep {
         match value {
             0 => Some(FileCopyStep::Initialise),
             10 => Some(FileCopyStep::Transfer),
+            20 => Some(FileCopyStep::Signatures),
+            30 => Some(FileCopyStep::Delta),
             200 => Some(FileCopyStep::End),
             _ => None,
         }
@@ -96,220 +402,876 @@ impl FileCopyStep {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryType {
+    File = 0,
+    Dir = 1,
+    Symlink = 2,
+    Other = 3,
+}
+impl EntryType {
+    pub fn from_u8(value: u8) -> Option<EntryType> {
+        match value {
+            0 => Some(EntryType::File),
+            1 => Some(EntryType::Dir),
+            2 => Some(EntryType::Symlink),
+            3 => Some(EntryType::Other),
+            _ => None,
+        }
+    }
 
-pub fn download_file_from_server(host:&str, port:u16, src:PathBuf, mut dest:PathBuf, is_continue:bool, chunk_size:Option<usize>) -> Result<(), Box<dyn Error>> {
-/*
-File Download:
-1. client: here is the relative path to the file. What is size of file, mtime, crc
-   server: here is size of file, mtime, crc
-2. client: for this relative path, give me the bytes from here to here
-   server: here are the bytes
-3. client: now check crc and mtime
-*/
+    pub fn to_u8(&self) -> u8 {
+        *self as u8
+    }
 
-	info!("receive_file_from_host start");
+    pub fn from_file_type(file_type: fs::FileType) -> EntryType {
+        if file_type.is_symlink() {
+            EntryType::Symlink
+        } else if file_type.is_dir() {
+            EntryType::Dir
+        } else if file_type.is_file() {
+            EntryType::File
+        } else {
+            EntryType::Other
+        }
+    }
 
-    let address = format!("{}:{}", host, port);
-	let chunk_size: usize = chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
-	
-	dest.push(src.file_name().expect("no filename in src"));
-	if !is_continue && dest.exists() {
-		fs::remove_file(&dest).expect(&format!("Could not delete file: {}", dest.to_string_lossy()));
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntryType::File => "file",
+            EntryType::Dir => "dir",
+            EntryType::Symlink => "symlink",
+            EntryType::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeType {
+    Subscribed = 0, //first event, the subscription is active
+    Created = 1,
+    Modified = 2,
+    Deleted = 3,
+    Heartbeat = 4, //nothing changed, sent so both ends know the connection is alive
+}
+impl ChangeType {
+    pub fn from_u8(value: u8) -> Option<ChangeType> {
+        match value {
+            0 => Some(ChangeType::Subscribed),
+            1 => Some(ChangeType::Created),
+            2 => Some(ChangeType::Modified),
+            3 => Some(ChangeType::Deleted),
+            4 => Some(ChangeType::Heartbeat),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeType::Subscribed => "subscribed",
+            ChangeType::Created => "created",
+            ChangeType::Modified => "modified",
+            ChangeType::Deleted => "deleted",
+            ChangeType::Heartbeat => "heartbeat",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    Fail = 0,
+    Overwrite = 1,
+    Skip = 2,
+}
+impl OverwritePolicy {
+    pub fn from_u8(value: u8) -> Option<OverwritePolicy> {
+        match value {
+            0 => Some(OverwritePolicy::Fail),
+            1 => Some(OverwritePolicy::Overwrite),
+            2 => Some(OverwritePolicy::Skip),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
+pub(crate) fn download_dest(src:&Path, dest:&Path) -> PathBuf {
+	//where a download of src is placed below dest. a src without a file name (. or ..) can only be a directory, its contents go straight into dest
+	match src.file_name() {
+		Some(name) => dest.join(name),
+		None => dest.to_path_buf(),
+	}
+}
+
+pub fn partial_path(path:&Path) -> PathBuf {
+	//a path without a file name is a directory, it never has a partial file
+	let name = path.file_name().unwrap_or_default().to_string_lossy();
+	path.with_file_name(format!(".{}{}", name, PARTIAL_SUFFIX))
+}
+
+pub fn ranges_path(path:&Path) -> PathBuf {
+	let name = path.file_name().unwrap_or_default().to_string_lossy();
+	path.with_file_name(format!(".{}{}", name, RANGES_SUFFIX))
+}
+
+pub fn is_partial_path(path:&Path) -> bool {
+	path.file_name().map(|name| {
+		let name = name.to_string_lossy();
+		name.starts_with('.') && (name.ends_with(PARTIAL_SUFFIX) || name.ends_with(RANGES_SUFFIX))
+	}).unwrap_or(false)
+}
+
+pub fn is_relative_path(path:&Path) -> bool {
+	//true for a non-empty path of plain names only, safe to join onto a local directory. rejects "..", "." and absolute paths
+	path.components().next().is_some() && path.components().all(|component| matches!(component, Component::Normal(_)))
+}
+
+pub fn set_mtime(path:&Path, mtime:u64) -> Result<(), std::io::Error> {
+	let file = OpenOptions::new().write(true).open(path)?;
+	let times = FileTimes::new()
+		.set_modified(unixtimestamp_to_systemtime(mtime));
+	file.set_times(times)
+}
+
+pub fn checksum_file_prefix(path:&Path, len:u64) -> Result<u64, std::io::Error> {
+	//crc of the first len bytes of a file, for checking a partial file before resuming
+	checksum_file_range(path, 0, len)
+}
+
+pub fn checksum_file_range(path:&Path, start:u64, len:u64) -> Result<u64, std::io::Error> {
+	Ok(digest_file_range(path, start, len)?.finalize())
+}
+
+pub fn digest_file_range(path:&Path, start:u64, len:u64) -> Result<Digest, std::io::Error> {
+	//as checksum_file_range, but the crc can go on to cover the bytes that come after the range
+	let mut digest = Digest::new(Crc64Nvme);
+	let mut file = File::open(path)?;
+	file.seek(std::io::SeekFrom::Start(start))?;
+	let mut file = file.take(len);
+	let mut buffer = vec![0u8; 1_048_576];
+	let mut nread: u64 = 0;
+	loop {
+		let nbytes = file.read(&mut buffer)?;
+		if nbytes == 0 {
+			break;
+		}
+		digest.update(&buffer[..nbytes]);
+		nread += nbytes as u64;
+	}
+	if nread < len {
+		Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, format!("{} is shorter than {} bytes", path.to_string_lossy(), start + len)))?;
+	}
+	Ok(digest)
+}
+
+pub struct DigestReader<R: Read> {
+    pub inner: R,
+    pub digest: Digest, //crc of every byte read through so far
+}
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let nbytes = self.inner.read(buf)?;
+        self.digest.update(&buf[..nbytes]);
+        Ok(nbytes)
+    }
+}
+
+pub struct DigestWriter<W: Write> {
+    pub inner: W,
+    pub digest: Digest, //crc of every byte written through so far
+}
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let nbytes = self.inner.write(buf)?;
+        self.digest.update(&buf[..nbytes]);
+        Ok(nbytes)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn write_frame(stream:&mut TcpStream, bytes:&[u8]) -> Result<(), std::io::Error> {
+	//length prefixed message, for connections that carry more than one
+	let frame_len: u64 = bytes.len() as u64;
+	stream.write_all(&frame_len.to_le_bytes())?;
+	stream.write_all(bytes)?;
+	stream.flush()
+}
+
+pub fn read_frame(stream:&mut TcpStream) -> Result<Option<Vec<u8>>, std::io::Error> {
+	//None when the peer closed the connection between frames
+	let mut frame_len = [0u8; 8];
+	match stream.read_exact(&mut frame_len) {
+		Ok(()) => {}
+		Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+		Err(e) => return Err(e),
+	}
+	let mut bytes = vec![0u8; u64::from_le_bytes(frame_len) as usize];
+	stream.read_exact(&mut bytes)?;
+	Ok(Some(bytes))
+}
+
+pub fn is_timeout(e:&std::io::Error) -> bool {
+	//a read or write timeout shows up as WouldBlock on unix and TimedOut on windows
+	matches!(e.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock)
+}
+
+pub(crate) fn request_error(address:&str, e:std::io::Error) -> Box<dyn Error> {
+	if is_timeout(&e) {
+		Box::new(TimeoutError {
+			address: address.to_string(),
+			source: e,
+		})
+	} else {
+		Box::new(e)
 	}
-	match dest.parent() {
-		Some(parent_dir) => {fs::create_dir_all(parent_dir)?;}
-		None => {}
+}
+
+pub fn configure_stream(stream:&TcpStream, read_timeout:Duration, write_timeout:Duration) -> Result<(), std::io::Error> {
+	stream.set_read_timeout(Some(read_timeout).filter(|timeout| !timeout.is_zero()))?;
+	stream.set_write_timeout(Some(write_timeout).filter(|timeout| !timeout.is_zero()))?;
+	//notices a peer that went away without closing, where a timeout is not set or a subscription is idle
+	SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(KEEPALIVE_TIME))
+}
+
+pub fn connect(address:&str, options:&TransferOptions) -> Result<TcpStream, std::io::Error> {
+	let mut last_error = std::io::Error::new(std::io::ErrorKind::NotFound, format!("Could not resolve {}", address));
+	for socket_addr in address.to_socket_addrs()? {
+		let stream = if options.connect_timeout.is_zero() {
+			TcpStream::connect(socket_addr)
+		} else {
+			TcpStream::connect_timeout(&socket_addr, options.connect_timeout)
+		};
+		match stream {
+			Ok(stream) => {
+				configure_stream(&stream, options.read_timeout, options.write_timeout)?;
+				return Ok(stream);
+			}
+			Err(e) => last_error = e,
+		}
 	}
+	Err(last_error)
+}
+
+pub fn copy_bytes(reader:&mut impl Read, writer:&mut impl Write, len:u64) -> Result<u64, std::io::Error> {
+	//copies up to len bytes, fewer if the reader ends first. returns how many were copied
+	let mut buffer = vec![0u8; len.min(STREAM_BUFFER_SIZE as u64) as usize];
+	let mut copied: u64 = 0;
+	while copied < len {
+		let max_read = (len - copied).min(buffer.len() as u64) as usize;
+		let nbytes = match reader.read(&mut buffer[..max_read]) {
+			Ok(0) => break,
+			Ok(nbytes) => nbytes,
+			Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e),
+		};
+		writer.write_all(&buffer[..nbytes])?;
+		copied += nbytes as u64;
+	}
+	Ok(copied)
+}
+
+#[cfg(target_os = "linux")]
+pub fn send_file_bytes(file:&mut File, stream:&mut TcpStream, len:u64) -> Result<u64, std::io::Error> {
+	//sendfile from the file's current position, the kernel moves the bytes to the socket without copying them through here
+	use std::os::fd::AsRawFd;
+	let mut sent: u64 = 0;
+	while sent < len {
+		let count = (len - sent).min(0x7fff_f000) as usize; //the most sendfile moves in one call
+		let nbytes = unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), std::ptr::null_mut(), count) };
+		if nbytes < 0 {
+			let e = std::io::Error::last_os_error();
+			match e.raw_os_error() {
+				Some(libc::EINTR) => continue,
+				Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => {
+					//a file system that cannot sendfile, the rest goes the buffered way
+					debug!("sendfile not available ({}), copying through a buffer", e);
+					return copy_bytes(file, stream, len - sent).map(|copied| sent + copied);
+				}
+				_ => return Err(e),
+			}
+		}
+		if nbytes == 0 {
+			break;
+		}
+		sent += nbytes as u64;
+	}
+	Ok(sent)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_file_bytes(file:&mut File, stream:&mut TcpStream, len:u64) -> Result<u64, std::io::Error> {
+	copy_bytes(file, stream, len)
+}
+
+pub(crate) fn read_full(reader:&mut impl Read, buffer:&mut [u8]) -> Result<usize, std::io::Error> {
+	//fills buffer unless the reader ends first, returns how much was read
+	let mut filled: usize = 0;
+	while filled < buffer.len() {
+		match reader.read(&mut buffer[filled..]) {
+			Ok(0) => break,
+			Ok(nbytes) => filled += nbytes,
+			Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(filled)
+}
+
+fn exchange(address:&str, package:&[u8], options:&TransferOptions) -> Result<Vec<u8>, std::io::Error> {
+	let mut stream = connect(address, options)?;
+	stream.write_all(package)?;
+	read_response(stream)
+}
+
+pub(crate) fn read_response(mut stream:TcpStream) -> Result<Vec<u8>, std::io::Error> {
+	stream.shutdown(std::net::Shutdown::Write)?;
+	let mut buffer_from_server = Vec::new();
+	let _n = stream.read_to_end(&mut buffer_from_server)?;
+	if buffer_from_server.is_empty() {
+		//the server closes the connection without a reply when its handler fails
+		Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed without a response"))?
+	}
+	Ok(buffer_from_server)
+}
+
+pub fn retry_backoff(options:&TransferOptions, attempt:u32) -> Duration {
+	let backoff = options.retry_backoff.saturating_mul(2u32.saturating_pow(attempt)).min(options.retry_backoff_max);
+	//wait between half and all of the backoff, so clients that failed together do not all retry together
+	let half_ms = backoff.as_millis() as u64 / 2;
+	let jitter_ms = (Uuid::new_v4().as_u128() as u64) % (half_ms + 1);
+	Duration::from_millis(half_ms + jitter_ms)
+}
+
+pub(crate) fn send_chunk(address:&str, head:&[u8], file:&mut File, offset:u64, len:u64, options:&TransferOptions) -> Result<(Vec<u8>, u64, Digest), std::io::Error> {
+	//sends head followed by len bytes of file from offset, straight from the file. returns the response, how many bytes the file had to send and their crc
+	let mut stream = connect(address, options)?;
+	stream.write_all(head)?;
+	file.seek(std::io::SeekFrom::Start(offset))?;
+	let mut reader = DigestReader {
+		inner: file,
+		digest: Digest::new(Crc64Nvme),
+	};
+	let nbytes = send_bytes(&mut reader, &mut stream, len, options.compression, options.compression_level)?;
+	Ok((read_response(stream)?, nbytes, reader.digest))
+}
+
+pub(crate) fn request_chunk(address:&str, package:&[u8], options:&TransferOptions) -> Result<(DownloadServerTransfer, TcpStream), std::io::Error> {
+	//sends a download Transfer request and reads the header of the reply, the file bytes after it are left on the stream
+	let mut stream = connect(address, options)?;
+	stream.write_all(package)?;
+	stream.shutdown(std::net::Shutdown::Write)?;
+	let mut header_len = [0u8; 8];
+	stream.read_exact(&mut header_len)?;
+	let mut header_bytes = vec![0u8; u64::from_le_bytes(header_len) as usize];
+	stream.read_exact(&mut header_bytes)?;
+	let download_server_transfer: DownloadServerTransfer = wincode::deserialize(&header_bytes).expect("Could not deserialize bytes to DownloadServerTransfer");
+	Ok((download_server_transfer, stream))
+}
+
+fn window_bytes(rate:Option<f64>, timeout:Duration) -> u64 {
+	//bytes of download chunk requests to keep in flight for a rate in bytes/s, none until the rate is known
+	match rate {
+		_ if timeout.is_zero() => u64::MAX,
+		Some(rate) => (rate * timeout.as_secs_f64() / 2.0) as u64,
+		None => 0,
+	}
+}
+
+fn reply_compression(download_server_transfer:&DownloadServerTransfer) -> Result<Compression, std::io::Error> {
+	Compression::from_u8(download_server_transfer.compression).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unknown compression in reply: {}", download_server_transfer.compression)))
+}
 
-    //inital package.
-	let download_client_initalise = DownloadClientInitalise {
+pub(crate) fn download_chunk(address:&str, package:&[u8], src_len_mtime:(u64, u64), file:&mut File, offset:u64, options:&TransferOptions, stats:&mut TransferStats) -> Result<(DownloadServerTransfer, u64), std::io::Error> {
+	//requests a chunk and streams its bytes into file at offset, unless the reply is an error or the file on the server is no longer src_len_mtime. returns the reply and the bytes written
+	with_retries(address, options, stats, || {
+		let (download_server_transfer, mut stream) = request_chunk(address, package, options)?;
+		if download_server_transfer.error_msg.is_some() || (download_server_transfer.filelen, download_server_transfer.mtime) != src_len_mtime {
+			return Ok((download_server_transfer, 0));
+		}
+		file.seek(std::io::SeekFrom::Start(offset))?;
+		let nbytes = receive_bytes(&mut stream, file, reply_compression(&download_server_transfer)?)?;
+		Ok((download_server_transfer, nbytes))
+	})
+}
+
+pub(crate) fn with_retries<T>(address:&str, options:&TransferOptions, stats:&mut TransferStats, mut request:impl FnMut() -> Result<T, std::io::Error>) -> Result<T, std::io::Error> {
+	//runs the request again while the connection fails. only for requests that are safe to send twice
+	let mut attempt: u32 = 0;
+	loop {
+		match request() {
+			Ok(response) => return Ok(response),
+			Err(e) => {
+				if attempt >= options.retries {
+					warn!("Request to {} failed after {} retries", address, attempt);
+					return Err(e);
+				}
+				let backoff = retry_backoff(options, attempt);
+				attempt += 1;
+				stats.retries += 1;
+				warn!("Request to {} failed: {}. Retry {}/{} in {:.1}s", address, e, attempt, options.retries, backoff.as_secs_f64());
+				std::thread::sleep(backoff);
+			}
+		}
+	}
+}
+pub(crate) fn exchange_with_retries(address:&str, package:&[u8], options:&TransferOptions, stats:&mut TransferStats) -> Result<Vec<u8>, std::io::Error> {
+	//sends a request and returns the response, retrying while the connection fails
+	with_retries(address, options, stats, || exchange(address, package, options))
+}
+
+fn send_request(address:&str, package:&[u8], options:&TransferOptions, stats:&mut TransferStats) -> Result<Vec<u8>, Box<dyn Error>> {
+	exchange_with_retries(address, package, options, stats).map_err(|e| request_error(address, e))
+}
+
+fn send_request_once(address:&str, package:&[u8], options:&TransferOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+	//only connecting is retried, a request that changes the server is not safe to send twice
+	let mut stream = with_retries(address, options, &mut TransferStats::default(), || connect(address, options)).map_err(|e| request_error(address, e))?;
+	stream.write_all(package).map_err(|e| request_error(address, e))?;
+	read_response(stream).map_err(|e| request_error(address, e))
+}
+
+fn download_crc(address:&str, src:&Path, download_server_initalise:&DownloadServerInitalise, options:&TransferOptions, stats:&mut TransferStats) -> Result<Option<u64>, Box<dyn Error>> {
+	//crc of the file from Initialise if the server knew it then, otherwise asked for with End. None if the file has changed on the server since
+	if let Some(crc) = download_server_initalise.crc {
+		return Ok(Some(crc));
+	}
+	let download_client_end = DownloadClientEnd {
 		serverside_path: src.to_string_lossy().to_string(),
+		filelen: download_server_initalise.filelen,
+		mtime: download_server_initalise.mtime,
 	};
-	let serialized = wincode::serialize(&download_client_initalise)?;
-	let step:FileCopyStep = FileCopyStep::Initialise;
-    let package = [SIGNATURE.to_vec(), vec![0u8], vec![step.to_u8()], serialized].concat();
-	let download_server_initalise: DownloadServerInitalise;
+	let serialized = wincode::serialize(&download_client_end)?;
+	let step:FileCopyStep = FileCopyStep::End;
+	let package = [SIGNATURE.to_vec(), vec![0u8], vec![step.to_u8()], serialized].concat();
+	let download_server_end: DownloadServerEnd;
 	{
-		info!("Connecting to server at {}...", address);
-		let mut stream = TcpStream::connect(&address)?;
-		stream.write_all(&package)?;
-		stream.shutdown(std::net::Shutdown::Write).expect("Error in write stream shutdown");
-		let mut buffer_from_server = Vec::new();
-		let _n = stream.read_to_end(&mut buffer_from_server)?;
-		// println!("{:?}", buffer_from_server);
-		download_server_initalise = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to DownloadServerInitalise");
-		debug!("download_server_initalise: {:#?}", download_server_initalise)
+		let buffer_from_server = send_request(address, &package, options, stats)?;
+		download_server_end = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to DownloadServerEnd");
 	}
-	if let Some(errmsg) = download_server_initalise.error_msg {
+	if let Some(errmsg) = download_server_end.error_msg {
 		error!("{errmsg}");
 		return Err(errmsg)?;
 	}
+	if download_server_end.filelen != download_server_initalise.filelen || download_server_end.mtime != download_server_initalise.mtime {
+		return Ok(None);
+	}
+	Ok(Some(download_server_end.crc))
+}
 
-	//download bytes until full or error
-	loop {
-		let filelen: u64;
-		if !dest.exists() {
-			filelen = 0;
-		} else {
-			let dest_metadata = dest.metadata().expect("error getting destination metadata");
-			filelen = dest_metadata.len();
-			if filelen >= download_server_initalise.filelen {
-				break;
+pub fn download_file_from_server(host:&str, port:u16, src:PathBuf, mut dest:PathBuf, is_continue:bool, options:&TransferOptions) -> Result<TransferStats, Box<dyn Error>> {
+/*
+File Download:
+1. client: here is the relative path to the file. What is size of file, mtime
+   server: here is size of file, mtime, and crc if it already knows it
+2. client: for this relative path, give me the bytes from here to here (several of these are in flight at once)
+   server: here are the bytes, and the file's current size and mtime. it works out the crc of what it sends as it goes
+3. client: for this relative path, what is the crc
+   server: here is the crc, reading only the parts of the file it has not sent
+   client: now check crc and mtime, then rename the partial file into place
+The bytes are written to a hidden partial file beside dest, so dest only ever holds a complete file.
+If the file changes on the server part way through, the partial download is discarded and it starts again at 1.
+A request that fails to reach the server is retried, a chunk resumes from the end of the partial file.
+*/
+
+	info!("receive_file_from_host start");
+
+    let address = format!("{}:{}", host, port);
+	
+	dest = download_dest(&src, &dest);
+	let part_path = partial_path(&dest);
+	let ranges_file = ranges_path(&dest);
+	if !is_continue && part_path.is_file() {
+		fs::remove_file(&part_path)?;
+	}
+	if !is_continue && ranges_file.is_file() {
+		fs::remove_file(&ranges_file)?;
+	}
+	if let Some(parent_dir) = dest.parent() {
+		fs::create_dir_all(parent_dir)?;
+	}
+
+	let mut stats = TransferStats::default();
+	'restart: loop {
+		//a partial file left by an earlier download is only resumed if the server confirms it is the start of the file
+		let mut resume_from: u64 = 0;
+		let mut prefix_crc: u64 = 0;
+		let mut resume_ranges: Vec<UploadRange> = Vec::new();
+		if is_continue && part_path.is_file() {
+			if ranges_file.is_file() {
+				//a ranged partial file has gaps, the server confirms each of its ranges instead
+				resume_ranges = partial_ranges(&part_path, &ranges_file);
+			} else {
+				resume_from = part_path.metadata()?.len();
+				prefix_crc = checksum_file_prefix(&part_path, resume_from)?;
 			}
 		}
-		if download_server_initalise.filelen==0 {
-			info!("creating empty 0 byte file {}", dest.to_string_lossy());
-			let _ = fs::write(&dest, &[])?;
-			// println!("{:#?}", r);
-			break;
+
+	    //inital package.
+		let download_client_initalise = DownloadClientInitalise {
+			serverside_path: src.to_string_lossy().to_string(),
+			resume_from: resume_from,
+			prefix_crc: prefix_crc,
+			ranges: resume_ranges,
+		};
+		let serialized = wincode::serialize(&download_client_initalise)?;
+		let step:FileCopyStep = FileCopyStep::Initialise;
+	    let package = [SIGNATURE.to_vec(), vec![0u8], vec![step.to_u8()], serialized].concat();
+		let download_server_initalise: DownloadServerInitalise;
+		{
+			info!("Connecting to server at {}...", address);
+			let buffer_from_server = send_request(&address, &package, options, &mut stats)?;
+			// println!("{:?}", buffer_from_server);
+			download_server_initalise = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to DownloadServerInitalise");
+			debug!("download_server_initalise: {:#?}", download_server_initalise)
+		}
+		if let Some(errmsg) = download_server_initalise.error_msg {
+			error!("{errmsg}");
+			return Err(errmsg)?;
+		}
+
+		//directory: recreate the tree and download each file into it
+		if download_server_initalise.is_dir {
+			//the names come from the server, never let one reach outside dest
+			if let Some(name) = download_server_initalise.dir_dirs.iter().chain(&download_server_initalise.dir_files).find(|name| !is_relative_path(Path::new(name))) {
+				Err(format!("Server sent a directory entry outside of {}: {}", src.to_string_lossy(), name))?
+			}
+			fs::create_dir_all(&dest)?;
+			for dir in &download_server_initalise.dir_dirs {
+				fs::create_dir_all(dest.join(dir))?;
+			}
+			let nfiles = download_server_initalise.dir_files.len();
+			for (ifile, file) in download_server_initalise.dir_files.iter().enumerate() {
+				info!("file {}/{}: {}", ifile+1, nfiles, file);
+				let file_dest = dest.join(file).parent().expect("no parent for file in directory").to_path_buf();
+				stats.add(&download_file_from_server(host, port, src.join(file), file_dest, is_continue, options)?);
+			}
+			return Ok(stats);
+		}
+		if resume_from > 0 && !download_server_initalise.prefix_matches {
+			warn!("Partial file {} does not match the start of the file on the server, restarting download", part_path.to_string_lossy());
+			fs::remove_file(&part_path)?;
+		}
+		//without a partial file, a finished dest that is already this file is kept as it is
+		if is_continue && resume_from == 0 && dest.is_file() && dest.metadata()?.len() == download_server_initalise.filelen {
+			//a file that changed on the server meanwhile is downloaded, where its Transfer replies show the change
+			if let Some(crc) = download_crc(&address, &src, &download_server_initalise, options, &mut stats)?
+				&& checksum_file(Crc64Nvme, &dest.to_string_lossy(), None)? == crc {
+				warn!("Identical file already exists in destination.");
+				set_mtime(&dest, download_server_initalise.mtime)?;
+				break 'restart;
+			}
+		}
+
+		//several connections each fill a byte range of a preallocated partial file. an interrupted ranged download is resumed the same way
+		let mut is_changed = false;
+		if download_server_initalise.filelen > 0 && (ranges_file.is_file() || (options.connections > 1 && download_server_initalise.filelen > options.chunk_size as u64)) {
+			is_changed = !download_ranges(&address, &src, &dest, &download_server_initalise, options, &mut stats)?;
 		} else {
-			info!("{:.1}% {}/{}", filelen as f64 / download_server_initalise.filelen as f64 * 100.0, format_bytes(filelen), format_bytes(download_server_initalise.filelen));
-			let download_client_transfer = DownloadClientTransfer {
-				serverside_path: src.to_string_lossy().to_string(),
-				from_byte: filelen,
-				chunk_size: chunk_size,
-			};
-			let serialized = wincode::serialize(&download_client_transfer)?;
-			let step:FileCopyStep = FileCopyStep::Transfer;
-			let package = [SIGNATURE.to_vec(), vec![0u8], vec![step.to_u8()], serialized].concat();
-			let download_server_transfer: DownloadServerTransfer;
-			let file_bytes: Vec<u8>;
-			{
-				let mut stream = TcpStream::connect(&address)?;
-				stream.write_all(&package)?;
-				stream.shutdown(std::net::Shutdown::Write).expect("Error in write stream shutdown");
-				let mut buffer_from_server = Vec::new();
-				let _n = stream.read_to_end(&mut buffer_from_server)?;
-				let header_len:[u8; 8] = buffer_from_server[0..8].try_into().expect("Could not convert header_len bytes to fixed length");
-				let header_len = u64::from_le_bytes(header_len);
-				let byte_starting_pos = 8+header_len as usize;
-				let header_bytes = &buffer_from_server[8..byte_starting_pos];
-				file_bytes = buffer_from_server[byte_starting_pos..].into();
-				download_server_transfer = wincode::deserialize(header_bytes).expect("Could not deserialize bytes to DownloadServerTransfer");
+			//download bytes until full or error. up to options.window chunk requests are in flight at once, the replies are written in order
+			//(a reply waits on its connection until its turn, so only the socket buffers hold it)
+			let mut in_flight: VecDeque<(u64, u64, ChunkRequest)> = VecDeque::new();
+			let mut next_from: u64 = if part_path.exists() {part_path.metadata()?.len()} else {0};
+			let mut failed_replies: u32 = 0;
+			//with requests in flight the time between finished chunks is what each one costs
+			let mut chunk_sizer = ChunkSizer::new(options);
+			let mut last_written = Instant::now();
+			let mut rate: Option<f64> = None;
+			loop {
+				if download_server_initalise.filelen==0 {
+					info!("creating empty 0 byte file {}", dest.to_string_lossy());
+					fs::write(&part_path, [])?;
+					// println!("{:#?}", r);
+					break;
+				}
+				//each reply waits in the server's write until the ones before it are read. to finish within its write timeout (taken to be
+				//the same as ours), no more than half a timeout's worth at the measured rate is in flight, and one chunk until it is measured
+				let max_in_flight_bytes = window_bytes(rate, options.read_timeout);
+				while in_flight.len() < options.window.max(1) && next_from < download_server_initalise.filelen
+					&& (in_flight.is_empty() || in_flight.iter().map(|(_, requested, _)| requested).sum::<u64>() + chunk_sizer.size() <= max_in_flight_bytes) {
+					let download_client_transfer = DownloadClientTransfer {
+						serverside_path: src.to_string_lossy().to_string(),
+						from_byte: next_from,
+						chunk_size: chunk_sizer.size() as usize,
+						compression: options.compression.to_u8(),
+						compression_level: options.compression_level,
+					};
+					let serialized = wincode::serialize(&download_client_transfer)?;
+					let step:FileCopyStep = FileCopyStep::Transfer;
+					let package = [SIGNATURE.to_vec(), vec![0u8], vec![step.to_u8()], serialized].concat();
+					let address = address.clone();
+					let options = options.clone();
+					in_flight.push_back((next_from, chunk_sizer.size(), thread::spawn(move || {
+						let mut chunk_stats = TransferStats::default();
+						with_retries(&address, &options, &mut chunk_stats, || request_chunk(&address, &package, &options)).map(|(download_server_transfer, chunk_stream)| (download_server_transfer, chunk_stream, chunk_stats))
+					})));
+					next_from += chunk_sizer.size();
+				}
+				let Some((from_byte, requested, chunk_request)) = in_flight.pop_front() else {
+					break;
+				};
+				info!("{:.1}% {}/{}", from_byte as f64 / download_server_initalise.filelen as f64 * 100.0, format_bytes(from_byte), format_bytes(download_server_initalise.filelen));
+				let download_server_transfer: DownloadServerTransfer;
+				let mut chunk_stream: TcpStream;
+				{
+					let chunk_stats: TransferStats;
+					(download_server_transfer, chunk_stream, chunk_stats) = chunk_request.join().expect("chunk request thread panicked").map_err(|e| request_error(&address, e))?;
+					stats.add(&chunk_stats);
+				}
+				//the file changed on the server since Initialise, the bytes so far belong to another version (a short read while it is rewritten is reported as an error too)
+				if download_server_transfer.filelen != download_server_initalise.filelen || download_server_transfer.mtime != download_server_initalise.mtime {
+					is_changed = true;
+					break;
+				}
+				if let Some(errmsg) = download_server_transfer.error_msg {
+					error!("{errmsg}");
+					return Err(errmsg)?;
+				}
+				let nbytes: u64;
+				{
+					let mut file = OpenOptions::new().append(true).create(true).open(&part_path)?;
+					match reply_compression(&download_server_transfer).and_then(|compression| receive_bytes(&mut chunk_stream, &mut file, compression)) {
+						Ok(copied) => {
+							nbytes = copied;
+							failed_replies = 0;
+							chunk_sizer.record(nbytes, last_written.elapsed());
+							rate = Some(nbytes as f64 / last_written.elapsed().as_secs_f64().max(0.001));
+						}
+						Err(e) => {
+							//what arrived before the connection failed is kept, the rest is asked for again like a short reply
+							if failed_replies >= options.retries {
+								return Err(request_error(&address, e));
+							}
+							failed_replies += 1;
+							stats.retries += 1;
+							warn!("Reply from {} broke off: {}. Retry {}/{}", address, e, failed_replies, options.retries);
+							thread::sleep(retry_backoff(options, failed_replies - 1));
+							nbytes = file.metadata()?.len() - from_byte;
+						}
+					}
+				}
+				last_written = Instant::now();
+				stats.bytes_transferred += nbytes;
+				//a short reply shifts every range requested after it, so those are dropped and asked for again from where the file ends
+				if nbytes < requested.min(download_server_initalise.filelen - from_byte) {
+					in_flight.clear();
+					next_from = from_byte + nbytes;
+				}
 			}
-			if let Some(errmsg) = download_server_transfer.error_msg {
-				error!("{errmsg}");
-				return Err(errmsg)?;
+		}
+		let crc = if is_changed {None} else {download_crc(&address, &src, &download_server_initalise, options, &mut stats)?};
+		let Some(crc) = crc else {
+			if stats.restarts >= options.max_restarts {
+				Err(format!("File changed on server during download, gave up after {} restarts: {}", stats.restarts, src.to_string_lossy()))?
 			}
-			{
-				let mut file = OpenOptions::new().write(true).append(true).create(true).open(&dest)?;
-				file.write_all(file_bytes.as_slice())?;
+			stats.restarts += 1;
+			warn!("File changed on server during download, restarting ({}/{}): {}", stats.restarts, options.max_restarts, src.to_string_lossy());
+			if part_path.is_file() {
+				fs::remove_file(&part_path)?;
+			}
+			if ranges_file.is_file() {
+				fs::remove_file(&ranges_file)?;
 			}
+			continue 'restart;
+		};
+
+		//check crc, a partial file that fails it is removed rather than resumed next time
+		let file_crc = checksum_file(Crc64Nvme, &part_path.to_string_lossy(), None).unwrap();
+		if file_crc != crc {
+			fs::remove_file(&part_path)?;
+			return Err("file crc mismatch")?;
 		}
-	}
+		set_mtime(&part_path, download_server_initalise.mtime)?;
+		fs::rename(&part_path, &dest)?;
 
-	//check crc
-	let file_crc = checksum_file(Crc64Nvme, &dest.to_string_lossy(), None).unwrap();
-	if file_crc != download_server_initalise.crc {
-		return Err("file crc mismatch")?;
+		break 'restart;
 	}
 
-	//set mtime
-	let mtime = unixtimestamp_to_systemtime(download_server_initalise.mtime);
-	{
-		let file = OpenOptions::new().write(true).open(dest)?;
-		let times = FileTimes::new()
-			.set_modified(mtime);
-		file.set_times(times)?;
-	}
+	Ok(stats)
+}
 
-	Ok(())
+pub(crate) fn file_len_mtime(path:&Path) -> Result<(u64, u64), std::io::Error> {
+	let metadata = path.metadata()?;
+	Ok((metadata.len(), systemtime_to_unixtimestamp(metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH))))
 }
 
-pub fn upload_file_to_server(host:&str, port:u16, src:PathBuf, mut dest:PathBuf, is_continue:bool, chunk_size:Option<usize>) -> Result<(), Box<dyn Error>> {
+pub fn upload_file_to_server(host:&str, port:u16, src:PathBuf, mut dest:PathBuf, is_continue:bool, options:&TransferOptions) -> Result<TransferStats, Box<dyn Error>> {
 /*
 File Upload:
 1. client: Here is the relative path to copy the file to, and if it should be continued or overwritten. What is it's current size.
-   server: I will delete the file if to be overwritten, and here is the current size.
-2. client: for this relative path, here are the bytes to append
-   server: OK
+   server: I will delete the partial file if to be overwritten, and here is the current size of the partial (or finished) file.
+2. client: for this relative path, here are the bytes to write at this offset
+   server: writes them to the partial file, and here is its length now
 3. client: for this relative path, here is the mtime to set to and the crc for checking.
-   server: sets mtime and checks crc
+   server: sets mtime and checks crc, then renames the partial file into place
+If the local file changes part way through, the upload starts again at 1 and overwrites what was sent.
+The crc is worked out from the bytes as they are sent, so the file is read once, and a resumed upload reads only the start the server already has to check it.
+A request that fails to reach the server is retried, a chunk is sent again to the same offset.
+With options.connections above 1 a large file goes as byte ranges in parallel instead of step 2 (see upload_ranges).
+With options.delta a different file already on the server is reported at 1, and step 2 sends only what differs from it (see upload_delta).
 */
 
     if !src.exists() || !src.is_file() {
 		Err(format!("Source path does not exist on client: {}", src.to_string_lossy()))?
 	}
     let address = format!("{}:{}", host, port);
-	let chunk_size: usize = chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE); //1MB
-
-	let src_metadata = src.metadata()?;
-	// let total_size = src_metadata.len();
-	let mtime = src_metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
-	let mtime = systemtime_to_unixtimestamp(mtime);
-	let filelen = src_metadata.len();
-	let file_crc = checksum_file(Crc64Nvme, &src.to_string_lossy(), None)?;
 
 	//dest add filename
 	dest.push(src.file_name().expect("no filename in src"));
-	let upload_client_initialise = UploadClientInitalise {
-		serverside_path: dest.to_string_lossy().to_string(),
-		is_continue: is_continue,
-	};
-	
-	let serialized = wincode::serialize(&upload_client_initialise)?;
-	let step:FileCopyStep = FileCopyStep::Initialise;
-    let package = [SIGNATURE.to_vec(), vec![1u8], vec![step.to_u8()], serialized].concat();
-	let upload_server_initalise: UploadServerInitalise;
-	{
-		info!("Connecting to server at {}...", address);
-		let mut stream = TcpStream::connect(&address)?;
-		stream.write_all(&package)?;
-		stream.shutdown(std::net::Shutdown::Write).expect("Error in write stream shutdown");
-		let mut buffer_from_server = Vec::new();
-		let _n = stream.read_to_end(&mut buffer_from_server)?;
-		// println!("{:?}", buffer_from_server);
-		upload_server_initalise = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to UploadServerInitalise");
-		debug!("upload_server_initalise: {:#?}", upload_server_initalise)
-	}
-	if let Some(errmsg) = upload_server_initalise.error_msg {
-		error!("{errmsg}");
-		return Err(errmsg)?;
-	}
+	let mut is_continue = is_continue;
+	let mut stats = TransferStats::default();
+	let (mtime, file_crc) = 'restart: loop {
+		let (filelen, mtime) = file_len_mtime(&src)?;
+		//a delta upload goes over one connection
+		let ranges: u32 = if options.connections > 1 && filelen > options.chunk_size as u64 && !options.delta {options.connections as u32} else {0};
 
-
-	//now we send file bytes, if any left to send.
-	if filelen>0 && upload_server_initalise.filelen == filelen  {
-		warn!("File of same size already exists in destination.");
-		return Ok(());
-	}
-	{
-		let mut file = File::open(src)?;
-		file.seek(std::io::SeekFrom::Start(upload_server_initalise.filelen))?;
-		let mut buffer = vec![0u8; chunk_size];
-		let mut iloop:i32 = 0;
-		loop {
-			let cur_pos = file.stream_position()?;
-			info!("{:.1}% {}/{}", cur_pos as f64 / filelen as f64 * 100.0, format_bytes(cur_pos), format_bytes(filelen));
-			let nbytes = file.read(&mut buffer)?;
-			if nbytes==0 && iloop>0 {
-				break;
-			}
-			let bytes: Vec<u8> = buffer[..nbytes].to_vec();
-			let upload_client_transfer: UploadClientTransfer = UploadClientTransfer {
+		let (upload_server_initalise, is_identical, prefix_digest) = loop {
+			let upload_client_initialise = UploadClientInitalise {
 				serverside_path: dest.to_string_lossy().to_string(),
+				is_continue: is_continue,
+				ranges: ranges,
+				filelen: filelen,
+				compression: options.compression.to_u8(),
+				delta: options.delta,
 			};
-			let serialized = wincode::serialize(&upload_client_transfer)?;
-			let header_len: u64 = serialized.len() as u64;
-			let step:FileCopyStep = FileCopyStep::Transfer;
-			let package = [SIGNATURE.to_vec(), vec![1u8], vec![step.to_u8()], header_len.to_le_bytes().to_vec(), serialized, bytes].concat();
-			let upload_server_transfer: UploadServerTransfer;
+
+			let serialized = wincode::serialize(&upload_client_initialise)?;
+			let step:FileCopyStep = FileCopyStep::Initialise;
+			let package = [SIGNATURE.to_vec(), vec![1u8], vec![step.to_u8()], serialized].concat();
+			let upload_server_initalise: UploadServerInitalise;
 			{
-				let mut stream = TcpStream::connect(&address)?;
-				stream.write_all(&package)?;
-				stream.shutdown(std::net::Shutdown::Write).expect("Error in write stream shutdown");
-				let mut buffer_from_server = Vec::new();
-				let _n = stream.read_to_end(&mut buffer_from_server)?;
-				// println!("buffer_from_server n: {}", _n);
-				upload_server_transfer = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to UploadServerTransfer");
+				info!("Connecting to server at {}...", address);
+				let buffer_from_server = send_request(&address, &package, options, &mut stats)?;
+				// println!("{:?}", buffer_from_server);
+				upload_server_initalise = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to UploadServerInitalise");
+				debug!("upload_server_initalise: {:#?}", upload_server_initalise)
 			}
-			if let Some(errmsg) = upload_server_transfer.error_msg {
+			if let Some(errmsg) = upload_server_initalise.error_msg {
 				error!("{errmsg}");
 				return Err(errmsg)?;
 			}
-			iloop+=1;
+			//the whole file is only read for its crc when it could be the finished file on the server, otherwise the crc is worked out as it is sent
+			let is_identical = upload_server_initalise.exists_complete && upload_server_initalise.filelen == filelen
+				&& checksum_file(Crc64Nvme, &src.to_string_lossy(), None)? == upload_server_initalise.prefix_crc;
+			let mut prefix_digest = Digest::new(Crc64Nvme);
+			if upload_server_initalise.exists_complete {
+				//a finished file is only kept if it is this file, otherwise a new partial file is started
+				if is_continue && !is_identical && !options.delta {
+					is_continue = false;
+					continue;
+				}
+			} else if is_continue && upload_server_initalise.ranges.is_empty() && upload_server_initalise.filelen > 0 {
+				//only resume if what the server holds is the start of this file, otherwise have it deleted and start again.
+				//the crc of that start goes on to cover the rest of the file as it is sent
+				let is_prefix = upload_server_initalise.filelen <= filelen && {
+					prefix_digest = digest_file_range(&src, 0, upload_server_initalise.filelen)?;
+					prefix_digest.finalize() == upload_server_initalise.prefix_crc
+				};
+				if !is_prefix {
+					warn!("File on server does not match the start of {}, restarting upload", src.to_string_lossy());
+					is_continue = false;
+					continue;
+				}
+			}
+			break (upload_server_initalise, is_identical, prefix_digest);
+		};
+
+		//now we send file bytes, if any left to send.
+		if upload_server_initalise.exists_complete && is_continue && is_identical {
+			warn!("Identical file already exists in destination.");
+			return Ok(stats);
 		}
-	}
+		//chunks are compressed only as the server agreed
+		let compression = Compression::from_u8(upload_server_initalise.compression).unwrap_or(Compression::None);
+		if compression != options.compression {
+			warn!("Server does not accept {} compression, sending uncompressed", options.compression.as_str());
+		}
+		let options = &TransferOptions {
+			compression: compression,
+			..options.clone()
+		};
+		//crc of the whole file as it was sent, none if it changed while being sent
+		let file_crc: Option<u64> = if upload_server_initalise.exists_complete {
+			//only with options.delta, the file on the server is the basis the new one is rebuilt from
+			upload_delta(&address, &src, &dest, upload_server_initalise.filelen, (filelen, mtime), options, &mut stats)?
+		} else if !upload_server_initalise.ranges.is_empty() {
+			upload_ranges(&address, &src, &dest, (filelen, mtime), &upload_server_initalise.ranges, options, &mut stats)?
+		} else {
+			let mut file = File::open(&src)?;
+			let mut digest = prefix_digest;
+			let mut is_changed = false;
+			let mut cur_pos: u64 = upload_server_initalise.filelen;
+			let mut chunk_sizer = ChunkSizer::new(options);
+			let mut iloop:i32 = 0;
+			loop {
+				if file_len_mtime(&src)? != (filelen, mtime) {
+					is_changed = true;
+					break;
+				}
+				info!("{:.1}% {}/{}", cur_pos as f64 / filelen as f64 * 100.0, format_bytes(cur_pos), format_bytes(filelen));
+				let nbytes = (filelen - cur_pos).min(chunk_sizer.size());
+				if nbytes==0 && iloop>0 {
+					break;
+				}
+				let upload_client_transfer: UploadClientTransfer = UploadClientTransfer {
+					serverside_path: dest.to_string_lossy().to_string(),
+					offset: cur_pos,
+					compression: options.compression.to_u8(),
+				};
+				let serialized = wincode::serialize(&upload_client_transfer)?;
+				let header_len: u64 = serialized.len() as u64;
+				let step:FileCopyStep = FileCopyStep::Transfer;
+				//the file bytes follow the header straight from the file
+				let head = [SIGNATURE.to_vec(), vec![1u8], vec![step.to_u8()], header_len.to_le_bytes().to_vec(), serialized].concat();
+				let upload_server_transfer: UploadServerTransfer;
+				let chunk_digest: Digest;
+				{
+					let sent_at = Instant::now();
+					let (buffer_from_server, nbytes_sent, sent_digest) = with_retries(&address, options, &mut stats, || send_chunk(&address, &head, &mut file, cur_pos, nbytes, options)).map_err(|e| request_error(&address, e))?;
+					if nbytes_sent < nbytes {
+						//src got shorter while being sent
+						is_changed = true;
+						break;
+					}
+					upload_server_transfer = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to UploadServerTransfer");
+					chunk_digest = sent_digest;
+					chunk_sizer.record(nbytes, sent_at.elapsed());
+				}
+				if let Some(errmsg) = upload_server_transfer.error_msg {
+					error!("{errmsg}");
+					return Err(errmsg)?;
+				}
+				if upload_server_transfer.filelen != cur_pos + nbytes {
+					Err(format!("File on server is {} bytes after writing up to byte {}", upload_server_transfer.filelen, cur_pos + nbytes))?
+				}
+				stats.bytes_transferred += nbytes;
+				digest.combine(&chunk_digest);
+				cur_pos += nbytes;
+				iloop+=1;
+			}
+			if is_changed {None} else {Some(digest.finalize())}
+		};
+		let Some(file_crc) = file_crc else {
+			if stats.restarts >= options.max_restarts {
+				Err(format!("File changed during upload, gave up after {} restarts: {}", stats.restarts, src.to_string_lossy()))?
+			}
+			stats.restarts += 1;
+			warn!("File changed during upload, restarting ({}/{}): {}", stats.restarts, options.max_restarts, src.to_string_lossy());
+			is_continue = false;
+			continue 'restart;
+		};
+
+		break 'restart (mtime, file_crc);
+	};
 
 	//end
 	let upload_client_end = UploadClientEnd {
@@ -322,11 +1284,7 @@ File Upload:
 	let package = [SIGNATURE.to_vec(), vec![1u8], vec![step.to_u8()], serialized].concat();
 	let upload_server_end: UploadServerEnd;
 	{
-		let mut stream = TcpStream::connect(&address)?;
-		stream.write_all(&package)?;
-		stream.shutdown(std::net::Shutdown::Write).expect("Error in write stream shutdown");
-		let mut buffer_from_server = Vec::new();
-		let _n = stream.read_to_end(&mut buffer_from_server)?;
+		let buffer_from_server = send_request(&address, &package, options, &mut stats)?;
 		upload_server_end = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to UploadServerEnd");
 	}
 	if let Some(errmsg) = upload_server_end.error_msg {
@@ -334,20 +1292,23 @@ File Upload:
 		return Err(errmsg)?;
 	}
 
-	Ok(())
+	Ok(stats)
 }
 
-pub fn delete_path_from_server(host:&str, port:u16, path:PathBuf) -> Result<(), Box<dyn Error>> {
+pub fn delete_path_from_server(host:&str, port:u16, path:PathBuf, recursive:bool, confirm:bool, dry_run:bool, options:&TransferOptions) -> Result<Vec<String>, Box<dyn Error>> {
 /*
 File Delete:
-1. client: Here is the relative path to the file to be deleted
-   server: I will delete the file
+1. client: Here is the relative path to the file or directory to be deleted, if it should be recursive (confirmed), or only a dry run
+   server: I will delete the path, and here is what was (or would be) removed
 */
 
     let address = format!("{}:{}", host, port);
 
 	let delete_client_initialise = DeleteClientInitalise {
 		serverside_path: path.to_string_lossy().to_string(),
+		recursive: recursive,
+		confirm: confirm,
+		dry_run: dry_run,
 	};
 	
 	let is_upload:u8 = 2;
@@ -357,13 +1318,9 @@ File Delete:
 	let delete_server_response: DeleteServerResponse;
 	{
 		info!("Connecting to server at {}...", address);
-		let mut stream = TcpStream::connect(&address)?;
-		stream.write_all(&package)?;
-		stream.shutdown(std::net::Shutdown::Write).expect("Error in write stream shutdown");
-		let mut buffer_from_server = Vec::new();
-		let _n = stream.read_to_end(&mut buffer_from_server)?;
+		let buffer_from_server = send_request_once(&address, &package, options)?;
 		// println!("{:?}", buffer_from_server);
-		delete_server_response = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to UploadServerInitalise");
+		delete_server_response = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to DeleteServerResponse");
 		debug!("delete_server_response: {:#?}", delete_server_response)
 	}
 	if let Some(errmsg) = delete_server_response.error_msg {
@@ -371,13 +1328,320 @@ File Delete:
 		return Err(errmsg)?;
 	}
 
+	Ok(delete_server_response.removed)
+}
+
+pub fn make_dir_on_server(host:&str, port:u16, path:PathBuf, options:&TransferOptions) -> Result<(), Box<dyn Error>> {
+/*
+Make Directory:
+1. client: Here is the relative path of the directory to create
+   server: I will create it and any missing parents (mkdir -p)
+*/
+
+    let address = format!("{}:{}", host, port);
+
+	let mkdir_client_initialise = MkdirClientInitalise {
+		serverside_path: path.to_string_lossy().to_string(),
+	};
+
+	let is_upload:u8 = 7;
+	let serialized = wincode::serialize(&mkdir_client_initialise)?;
+	let step:FileCopyStep = FileCopyStep::Initialise;
+    let package: Vec<u8> = [SIGNATURE.to_vec(), vec![is_upload], vec![step.to_u8()], serialized].concat();
+	let mkdir_server_response: MkdirServerResponse;
+	{
+		info!("Connecting to server at {}...", address);
+		let buffer_from_server = send_request(&address, &package, options, &mut TransferStats::default())?;
+		mkdir_server_response = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to MkdirServerResponse");
+		debug!("mkdir_server_response: {:#?}", mkdir_server_response)
+	}
+	if let Some(errmsg) = mkdir_server_response.error_msg {
+		error!("{errmsg}");
+		return Err(errmsg)?;
+	}
+	if !mkdir_server_response.created {
+		info!("Directory already exists on server: {}", path.to_string_lossy());
+	}
+
+	Ok(())
+}
+
+pub fn list_path_on_server(host:&str, port:u16, path:PathBuf, recursive:bool, max_depth:Option<u32>, include_crc:bool, options:&TransferOptions) -> Result<Vec<ListEntry>, Box<dyn Error>> {
+/*
+List:
+1. client: Here is the relative path to list, whether to recurse and how deep, and if crcs are wanted
+   server: here are the entries (path, type, size, mtime, crc)
+*/
+
+    let address = format!("{}:{}", host, port);
+
+	let list_client_initialise = ListClientInitalise {
+		serverside_path: path.to_string_lossy().to_string(),
+		recursive: recursive,
+		max_depth: max_depth.unwrap_or(0),
+		include_crc: include_crc,
+	};
+
+	let is_upload:u8 = 3;
+	let serialized = wincode::serialize(&list_client_initialise)?;
+	let step:FileCopyStep = FileCopyStep::Initialise;
+    let package: Vec<u8> = [SIGNATURE.to_vec(), vec![is_upload], vec![step.to_u8()], serialized].concat();
+	let list_server_response: ListServerResponse;
+	{
+		info!("Connecting to server at {}...", address);
+		let buffer_from_server = send_request(&address, &package, options, &mut TransferStats::default())?;
+		list_server_response = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to ListServerResponse");
+	}
+	if let Some(errmsg) = list_server_response.error_msg {
+		error!("{errmsg}");
+		return Err(errmsg)?;
+	}
+
+	Ok(list_server_response.entries)
+}
+
+pub fn stat_path_on_server(host:&str, port:u16, path:PathBuf, include_hash:bool, options:&TransferOptions) -> Result<StatServerResponse, Box<dyn Error>> {
+/*
+Stat:
+1. client: Here is the relative path, and if the crc should be computed
+   server: here is whether it exists, its type, size, mtime, permissions, symlink target and crc
+*/
+
+    let address = format!("{}:{}", host, port);
+
+	let stat_client_initialise = StatClientInitalise {
+		serverside_path: path.to_string_lossy().to_string(),
+		include_hash: include_hash,
+	};
+
+	let is_upload:u8 = 4;
+	let serialized = wincode::serialize(&stat_client_initialise)?;
+	let step:FileCopyStep = FileCopyStep::Initialise;
+    let package: Vec<u8> = [SIGNATURE.to_vec(), vec![is_upload], vec![step.to_u8()], serialized].concat();
+	let stat_server_response: StatServerResponse;
+	{
+		debug!("Connecting to server at {}...", address);
+		let buffer_from_server = send_request(&address, &package, options, &mut TransferStats::default())?;
+		stat_server_response = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to StatServerResponse");
+		debug!("stat_server_response: {:#?}", stat_server_response)
+	}
+	if let Some(errmsg) = stat_server_response.error_msg {
+		error!("{errmsg}");
+		return Err(errmsg)?;
+	}
+
+	Ok(stat_server_response)
+}
+
+pub fn move_path_on_server(host:&str, port:u16, from:PathBuf, to:PathBuf, overwrite_policy:OverwritePolicy, options:&TransferOptions) -> Result<(), Box<dyn Error>> {
+/*
+Move/Rename:
+1. client: Here is the relative path to move, the relative path to move it to, and what to do if that exists
+   server: renames (falls back to copy and delete across filesystems)
+*/
+
+    let address = format!("{}:{}", host, port);
+
+	let move_client_initialise = MoveClientInitalise {
+		serverside_from: from.to_string_lossy().to_string(),
+		serverside_to: to.to_string_lossy().to_string(),
+		overwrite_policy: overwrite_policy.to_u8(),
+	};
+
+	let is_upload:u8 = 5;
+	let serialized = wincode::serialize(&move_client_initialise)?;
+	let step:FileCopyStep = FileCopyStep::Initialise;
+    let package: Vec<u8> = [SIGNATURE.to_vec(), vec![is_upload], vec![step.to_u8()], serialized].concat();
+	let move_server_response: MoveServerResponse;
+	{
+		info!("Connecting to server at {}...", address);
+		let buffer_from_server = send_request_once(&address, &package, options)?;
+		move_server_response = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to MoveServerResponse");
+		debug!("move_server_response: {:#?}", move_server_response)
+	}
+	if let Some(errmsg) = move_server_response.error_msg {
+		error!("{errmsg}");
+		return Err(errmsg)?;
+	}
+	if move_server_response.skipped {
+		warn!("Destination already exists on server, skipped move: {}", to.to_string_lossy());
+	}
+
+	Ok(())
+}
+
+pub fn copy_path_on_server(host:&str, port:u16, from:PathBuf, to:PathBuf, overwrite_policy:OverwritePolicy, options:&TransferOptions) -> Result<(), Box<dyn Error>> {
+/*
+Server-side Copy:
+1. client: Here is the relative path to copy, the relative path to copy it to, and what to do if that exists
+   server: copies the file or directory tree, keeping mtimes
+*/
+
+    let address = format!("{}:{}", host, port);
+
+	let copy_client_initialise = CopyClientInitalise {
+		serverside_from: from.to_string_lossy().to_string(),
+		serverside_to: to.to_string_lossy().to_string(),
+		overwrite_policy: overwrite_policy.to_u8(),
+	};
+
+	let is_upload:u8 = 6;
+	let serialized = wincode::serialize(&copy_client_initialise)?;
+	let step:FileCopyStep = FileCopyStep::Initialise;
+    let package: Vec<u8> = [SIGNATURE.to_vec(), vec![is_upload], vec![step.to_u8()], serialized].concat();
+	let copy_server_response: CopyServerResponse;
+	{
+		info!("Connecting to server at {}...", address);
+		let buffer_from_server = send_request_once(&address, &package, options)?;
+		copy_server_response = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to CopyServerResponse");
+		debug!("copy_server_response: {:#?}", copy_server_response)
+	}
+	if let Some(errmsg) = copy_server_response.error_msg {
+		error!("{errmsg}");
+		return Err(errmsg)?;
+	}
+	if copy_server_response.skipped {
+		warn!("Destination already exists on server, skipped copy: {}", to.to_string_lossy());
+	}
+
+	Ok(())
+}
+
+pub fn subscribe_to_server<F>(host:&str, port:u16, path:PathBuf, settle:Duration, options:&TransferOptions, mut on_event:F) -> Result<(), Box<dyn Error>>
+where F: FnMut(SubscribeServerEvent) -> Result<(), Box<dyn Error>> {
+/*
+Subscribe:
+1. client: Here is the relative path of a directory to follow, and how long changes should settle
+   server: keeps the connection open and sends an event frame for each settled change below it, and a heartbeat when idle
+Returns when the server closes the connection, or with the first error from on_event.
+Hearing nothing, not even a heartbeat, for the read timeout is a TimeoutError.
+*/
+
+    let address = format!("{}:{}", host, port);
+
+	let subscribe_client_initialise = SubscribeClientInitalise {
+		serverside_path: path.to_string_lossy().to_string(),
+		settle_ms: settle.as_millis() as u64,
+	};
+
+	let is_upload:u8 = 8;
+	let serialized = wincode::serialize(&subscribe_client_initialise)?;
+	let step:FileCopyStep = FileCopyStep::Initialise;
+    let package: Vec<u8> = [SIGNATURE.to_vec(), vec![is_upload], vec![step.to_u8()], serialized].concat();
+	info!("Connecting to server at {}...", address);
+	let mut stream = connect(&address, options).map_err(|e| request_error(&address, e))?;
+	stream.write_all(&package).map_err(|e| request_error(&address, e))?;
+	stream.shutdown(std::net::Shutdown::Write).map_err(|e| request_error(&address, e))?;
+	while let Some(frame) = read_frame(&mut stream).map_err(|e| request_error(&address, e))? {
+		let subscribe_server_event: SubscribeServerEvent = wincode::deserialize(&frame).expect("Could not deserialize bytes to SubscribeServerEvent");
+		debug!("subscribe_server_event: {:#?}", subscribe_server_event);
+		if let Some(errmsg) = subscribe_server_event.error_msg {
+			error!("{errmsg}");
+			return Err(errmsg)?;
+		}
+		if ChangeType::from_u8(subscribe_server_event.change_type) == Some(ChangeType::Heartbeat) {
+			continue;
+		}
+		on_event(subscribe_server_event)?;
+	}
+	info!("Subscription closed by server");
+
 	Ok(())
 }
 
 // cargo test -- --nocapture
 #[cfg(test)]
 mod tests {
-	// use super::*;
+	use super::*;
+
+    fn sizer(chunk_size: usize, min_chunk_size: usize, max_chunk_size: usize) -> ChunkSizer {
+        ChunkSizer::new(&TransferOptions {
+            chunk_size: chunk_size,
+            min_chunk_size: min_chunk_size,
+            max_chunk_size: max_chunk_size,
+            chunk_target: Duration::from_secs(2),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_chunk_sizer_grows_to_max() {
+        //a chunk that took a millisecond would want to be far bigger, it only doubles each time
+        let mut chunk_sizer = sizer(1_000_000, 1_000, 10_000_000);
+        let mut last_size = chunk_sizer.size();
+        for _ in 0..10 {
+            chunk_sizer.record(chunk_sizer.size(), Duration::from_millis(1));
+            assert!(chunk_sizer.size() <= last_size * 2 && chunk_sizer.size() <= 10_000_000);
+            last_size = chunk_sizer.size();
+        }
+        assert_eq!(chunk_sizer.size(), 10_000_000);
+    }
+
+    #[test]
+    fn test_chunk_sizer_shrinks_to_min() {
+        let mut chunk_sizer = sizer(1_000_000, 100_000, 10_000_000);
+        let mut last_size = chunk_sizer.size();
+        for _ in 0..10 {
+            chunk_sizer.record(chunk_sizer.size(), Duration::from_secs(60));
+            assert!(chunk_sizer.size() >= last_size / 2 && chunk_sizer.size() >= 100_000);
+            last_size = chunk_sizer.size();
+        }
+        assert_eq!(chunk_sizer.size(), 100_000);
+    }
+
+    #[test]
+    fn test_chunk_sizer_settles_on_target() {
+        //1MB/s with a 2s target
+        let mut chunk_sizer = sizer(1_000_000, 1_000, 10_000_000);
+        chunk_sizer.record(1_000_000, Duration::from_secs(1));
+        assert_eq!(chunk_sizer.size(), 2_000_000);
+        chunk_sizer.record(2_000_000, Duration::from_secs(2));
+        assert_eq!(chunk_sizer.size(), 2_000_000);
+    }
+
+    #[test]
+    fn test_chunk_sizer_bounds() {
+        //a first size outside the bounds is brought inside them, and a record with nothing sent or no time taken stays inside
+        assert_eq!(sizer(50, 1_000, 10_000).size(), 1_000);
+        assert_eq!(sizer(50_000, 1_000, 10_000).size(), 10_000);
+        let mut chunk_sizer = sizer(5_000, 1_000, 10_000);
+        chunk_sizer.record(0, Duration::ZERO);
+        assert_eq!(chunk_sizer.size(), 5_000);
+        chunk_sizer.record(5_000, Duration::ZERO);
+        assert_eq!(chunk_sizer.size(), 10_000);
+    }
+
+    #[test]
+    fn test_chunk_sizer_fixed() {
+        //with no target the chunk size is what was asked for, outside the bounds or not
+        let mut chunk_sizer = ChunkSizer::new(&TransferOptions {
+            chunk_size: 50,
+            min_chunk_size: 1_000,
+            chunk_target: Duration::ZERO,
+            ..Default::default()
+        });
+        chunk_sizer.record(50, Duration::from_millis(1));
+        assert_eq!(chunk_sizer.size(), 50);
+    }
+
+    #[test]
+    fn test_window_bytes() {
+        //half a timeout's worth at the measured rate, none before it is measured, and no bound without a timeout
+        assert_eq!(window_bytes(Some(1_000_000.0), Duration::from_secs(60)), 30_000_000);
+        assert_eq!(window_bytes(None, Duration::from_secs(60)), 0);
+        assert_eq!(window_bytes(None, Duration::ZERO), u64::MAX);
+    }
+
+    #[test]
+    fn test_is_relative_path() {
+        assert!(is_relative_path(Path::new("a.txt")));
+        assert!(is_relative_path(Path::new("dir/sub/a.txt")));
+        assert!(!is_relative_path(Path::new("")));
+        assert!(!is_relative_path(Path::new(".")));
+        assert!(!is_relative_path(Path::new("../a.txt")));
+        assert!(!is_relative_path(Path::new("dir/../../a.txt")));
+        assert!(!is_relative_path(Path::new("/etc/passwd")));
+    }
 
     // #[test]
     // fn test_send_file_to_host() {
@@ -389,7 +1653,7 @@ mod tests {
     // fn test_send_file_to_host_large() {
 	// 	let host = "127.0.0.1";
 	// 	// let host = "XXPA201LAP00072.local";
-	// 	let result = upload_file_to_server(host, 52709, PathBuf::from("./tests/Bremshley Treadmill Service Manual.pdf"), PathBuf::from("./large"), true, None).unwrap();
+	// 	let result = upload_file_to_server(host, 52709, PathBuf::from("./tests/Bremshley Treadmill Service Manual.pdf"), PathBuf::from("./large"), true, &TransferOptions::default()).unwrap();
 	// 	assert_eq!(result, ());
     // }
 
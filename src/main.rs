@@ -1,14 +1,26 @@
-use crc_fast::{checksum_file, CrcAlgorithm::Crc64Nvme};
-use helper_lib::{setup_logger, datetime::{systemtime_to_unixtimestamp, unixtimestamp_to_systemtime}};
+//fields are written out as field: field and errors returned as return Err(...)?, throughout the crate
+#![allow(clippy::redundant_field_names, clippy::needless_return_with_question_mark)]
+
+use crc_fast::{checksum_file, CrcAlgorithm::Crc64Nvme, Digest};
+use helper_lib::{setup_logger, datetime::{systemtime_to_unixtimestamp, unixtimestamp_to_systemtime}, paths::format_bytes};
 use log::*;
+use notify::{EventKind, RecursiveMode, Watcher, event::ModifyKind};
 use std::fs::{self, File, FileTimes, OpenOptions};
-use std::io::{Read, Write, Seek};
+use std::io::{BufWriter, Read, Write, Seek};
 use std::net::{TcpListener, TcpStream};
-use std::path::{PathBuf, absolute};
-use std::time::{SystemTime};
+use std::path::{Component, Path, PathBuf, absolute};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::time::{Duration, Instant, SystemTime};
 use std::{env, process, thread};
 use std::error::Error;
-use tcp_file_copy::{DeleteClientInitalise, DeleteServerResponse, DownloadClientInitalise, DownloadClientTransfer, DownloadServerInitalise, DownloadServerTransfer, FileCopyStep, SIGNATURE, UploadClientEnd, UploadClientInitalise, UploadClientTransfer, UploadServerEnd, UploadServerInitalise, UploadServerTransfer, delete_path_from_server, download_file_from_server, upload_file_to_server};
+use uuid::Uuid;
+use tcp_file_copy::compress::{Compression, receive_bytes, send_bytes};
+use tcp_file_copy::delta::{apply_delta, block_signatures};
+use tcp_file_copy::ranges::{RangesState, read_ranges, write_ranges};
+use tcp_file_copy::sync::{SyncActionType, SyncOptions, sync_dir_to_server, sync_dir_two_way};
+use tcp_file_copy::watch::{DEFAULT_SETTLE, PendingPaths, follow_server_dir, watch_and_upload};
+use tcp_file_copy::{BlockSignature, ChangeType, CopyClientInitalise, CopyServerResponse, DEFAULT_HASH_CACHE_ENTRIES, DEFAULT_IO_TIMEOUT, DEFAULT_MAX_CONNECTIONS, DeleteClientInitalise, DeleteServerResponse, DigestReader, DigestWriter, DownloadClientEnd, DownloadClientInitalise, DownloadClientTransfer, DownloadServerEnd, DownloadServerInitalise, DownloadServerTransfer, EntryType, FileCopyStep, HEARTBEAT_INTERVAL, ListClientInitalise, ListEntry, ListServerResponse, MkdirClientInitalise, MkdirServerResponse, MoveClientInitalise, MoveServerResponse, OverwritePolicy, SIGNATURE, STREAM_BUFFER_SIZE, StatClientInitalise, StatServerResponse, SubscribeClientInitalise, SubscribeServerEvent, TransferOptions, UploadClientDelta, UploadClientEnd, UploadClientInitalise, UploadClientSignatures, UploadClientTransfer, UploadRange, UploadServerEnd, UploadServerInitalise, UploadServerSignatures, UploadServerTransfer, configure_stream, copy_bytes, copy_path_on_server, delete_path_from_server, digest_file_range, download_file_from_server, is_partial_path, list_path_on_server, make_dir_on_server, move_path_on_server, partial_path, ranges_path, send_file_bytes, stat_path_on_server, upload_file_to_server, write_frame};
 
 fn get_full_path(root_path:Option<PathBuf>, serverside_path:String) -> PathBuf {
     match root_path {
@@ -17,11 +29,633 @@ fn get_full_path(root_path:Option<PathBuf>, serverside_path:String) -> PathBuf {
     }
 }
 
-fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+fn normalize_path(path:&Path) -> PathBuf {
+    //lexically resolves . and .. components, symlinks are not resolved
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn is_within_root(root_path:&Option<PathBuf>, full_path:&Path) -> bool {
+    match root_path {
+        Some(root_path) => {
+            let root = normalize_path(&absolute(root_path).unwrap_or(root_path.clone()));
+            normalize_path(full_path).starts_with(root)
+        }
+        None => true,
+    }
+}
+
+fn is_root(root_path:&Option<PathBuf>, full_path:&Path) -> bool {
+    match root_path {
+        Some(root_path) => normalize_path(&absolute(root_path).unwrap_or(root_path.clone())) == normalize_path(full_path),
+        None => false,
+    }
+}
+
+fn permissions_mode(metadata:&fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        if metadata.permissions().readonly() {0o444} else {0o666}
+    }
+}
+
+fn list_entry(path:&Path, rel:String, entry_type:EntryType, include_crc:bool) -> ListEntry {
+    //symlinks are described as links, their targets are neither followed nor hashed
+    let metadata = fs::symlink_metadata(path);
+    let mut size: u64 = 0;
+    let mut mtime: u64 = 0;
+    let mut crc: Option<u64> = None;
+    if let Ok(metadata) = metadata {
+        if !metadata.is_dir() {
+            size = metadata.len();
+        }
+        mtime = systemtime_to_unixtimestamp(metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+        if include_crc && metadata.file_type().is_file() {
+            crc = checksum_file(Crc64Nvme, &path.to_string_lossy(), None).ok();
+        }
+    }
+    ListEntry {
+        path: rel,
+        entry_type: entry_type.to_u8(),
+        size: size,
+        mtime: mtime,
+        crc: crc,
+    }
+}
+
+fn list_dir(dir:&Path, rel:&str, depth:u32, max_depth:u32, include_crc:bool, include_partial:bool, entries:&mut Vec<ListEntry>) -> Result<(), std::io::Error> {
+    //collects entries below dir with relative paths ('/' separated). max_depth 0 = no limit. symlinked directories are not followed.
+    //partial and ranges files of unfinished uploads are skipped unless include_partial
+    let mut dir_entries: Vec<fs::DirEntry> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+    dir_entries.sort_by_key(|entry| entry.file_name());
+    for entry in dir_entries {
+        if !include_partial && is_partial_path(&entry.path()) {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        let entry_rel = if rel.is_empty() {name} else {format!("{}/{}", rel, name)};
+        let path = entry.path();
+        let entry_type = EntryType::from_file_type(entry.file_type()?);
+        entries.push(list_entry(&path, entry_rel.clone(), entry_type, include_crc));
+        if entry_type == EntryType::Dir && (max_depth == 0 || depth < max_depth) {
+            list_dir(&path, &entry_rel, depth+1, max_depth, include_crc, include_partial, entries)?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_path(path:&Path) -> Result<(), std::io::Error> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+fn copy_symlink(from:&Path, to:&Path) -> Result<(), std::io::Error> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)
+    }
+    #[cfg(not(unix))]
+    {
+        warn!("Skipping symlink when copying on server: {}", from.to_string_lossy());
+        Ok(())
+    }
+}
+
+fn copy_tree(from:&Path, to:&Path) -> Result<(), std::io::Error> {
+    //copies a file or directory tree, keeping file mtimes. symlinks are recreated as links and never followed, existing files below to are overwritten
+    let metadata = fs::symlink_metadata(from)?;
+    if let Ok(to_metadata) = fs::symlink_metadata(to) {
+        //never write through an existing link in the destination
+        if to_metadata.is_symlink() || metadata.is_symlink() || to_metadata.is_dir() != metadata.is_dir() {
+            remove_path(to)?;
+        }
+    }
+    if metadata.is_symlink() {
+        copy_symlink(from, to)?;
+    } else if metadata.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(from, to)?;
+        let file = OpenOptions::new().write(true).open(to)?;
+        file.set_times(FileTimes::new().set_modified(metadata.modified()?))?;
+    }
+    Ok(())
+}
+
+fn copy_tree_or_remove(from:&Path, to:&Path) -> Result<(), std::io::Error> {
+    //a failed copy removes what it created. a destination that already existed is left as it is
+    let to_existed = fs::symlink_metadata(to).is_ok();
+    let result = copy_tree(from, to);
+    if result.is_err() && !to_existed && let Err(e) = remove_path(to) {
+        warn!("Error removing partial copy {} on server: {}", to.to_string_lossy(), e);
+    }
+    result
+}
+
+fn check_destination(from:&Path, to:&Path, overwrite_policy:OverwritePolicy) -> Result<bool, String> {
+    //returns true if the move/copy should be skipped. an existing destination is left for the caller to replace
+    if fs::symlink_metadata(from).is_err() {
+        Err(format!("Path does not exist on server: {}", from.to_string_lossy()))?;
+    }
+    if normalize_path(to).starts_with(normalize_path(from)) {
+        Err(format!("Destination {} is the same as or inside source {}", to.to_string_lossy(), from.to_string_lossy()))?;
+    }
+    if fs::symlink_metadata(to).is_ok() {
+        match overwrite_policy {
+            OverwritePolicy::Fail => Err(format!("Destination already exists on server: {}", to.to_string_lossy()))?,
+            OverwritePolicy::Skip => return Ok(true),
+            OverwritePolicy::Overwrite => {}
+        }
+    }
+    if let Some(parent_dir) = to.parent() {
+        fs::create_dir_all(parent_dir).map_err(|e| format!("Error creating dirs on server: {}", e))?;
+    }
+    Ok(false)
+}
+
+fn move_path(from:&Path, to:&Path, overwrite_policy:OverwritePolicy) -> Result<bool, String> {
+    if check_destination(from, to, overwrite_policy)? {
+        return Ok(true);
+    }
+    //rename cannot replace a directory, or a file with a directory. such a destination is set aside until the source is in its place
+    let is_from_dir = fs::symlink_metadata(from).is_ok_and(|from_metadata| from_metadata.is_dir());
+    let aside_path = match fs::symlink_metadata(to) {
+        Ok(to_metadata) if to_metadata.is_dir() || is_from_dir => {
+            let name = to.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+            let aside_path = to.with_file_name(format!(".{}.{}.tfcmove", name, Uuid::new_v4()));
+            fs::rename(to, &aside_path).map_err(|e| format!("Error setting aside existing destination on server: {}", e))?;
+            Some(aside_path)
+        }
+        _ => None,
+    };
+    let result = match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            //not atomic across filesystems: copy then remove the source
+            copy_tree_or_remove(from, to).map_err(|e| format!("Error copying across filesystems on server: {}", e))
+                .and_then(|()| remove_path(from).map_err(|e| format!("Error removing source after copy on server: {}", e)))
+        }
+        Err(e) => Err(format!("Error moving {} to {} on server: {}", from.to_string_lossy(), to.to_string_lossy(), e)),
+    };
+    if let Some(aside_path) = aside_path {
+        if result.is_ok() {
+            if let Err(e) = remove_path(&aside_path) {
+                warn!("Error removing replaced destination {} on server: {}", aside_path.to_string_lossy(), e);
+            }
+        } else if fs::symlink_metadata(to).is_err() && let Err(e) = fs::rename(&aside_path, to) {
+            warn!("Error putting back destination {} on server, it is kept as {}: {}", to.to_string_lossy(), aside_path.to_string_lossy(), e);
+        }
+    }
+    result?;
+    Ok(false)
+}
+
+fn copy_path(from:&Path, to:&Path, overwrite_policy:OverwritePolicy) -> Result<bool, String> {
+    if check_destination(from, to, overwrite_policy)? {
+        return Ok(true);
+    }
+    let is_from_dir = fs::symlink_metadata(from).is_ok_and(|from_metadata| from_metadata.is_dir());
+    if let Ok(to_metadata) = fs::symlink_metadata(to) && to_metadata.is_dir() != is_from_dir {
+        //copy_tree writes into an existing destination of the same type only
+        remove_path(to).map_err(|e| format!("Error removing existing destination on server: {}", e))?;
+    }
+    copy_tree_or_remove(from, to).map_err(|e| format!("Error copying {} to {} on server: {}", from.to_string_lossy(), to.to_string_lossy(), e))?;
+    Ok(false)
+}
+
+fn rel_string(path:&Path, root:&Path) -> Option<String> {
+    //'/' separated path of path below root
+    let rel = path.strip_prefix(root).ok()?;
+    Some(rel.components().map(|component| component.as_os_str().to_string_lossy().to_string()).collect::<Vec<String>>().join("/"))
+}
+
+//ranged upload progress is read and written back by each Transfer, connections for the same file must take turns
+static RANGES_LOCK: Mutex<()> = Mutex::new(());
+
+fn prepare_upload_ranges(full_path:&Path, is_continue:bool, filelen:u64, count:usize) -> Result<Vec<UploadRange>, std::io::Error> {
+    //preallocates the partial file of a ranged upload and records the progress of each range next to it, or picks up that of an interrupted one. no ranges for a sequential upload
+    let part_path = partial_path(full_path);
+    let ranges_file = ranges_path(full_path);
+    let _guard = RANGES_LOCK.lock().expect("ranges lock poisoned");
+    let resumed = read_ranges(&ranges_file).filter(|state| {
+        is_continue
+            && count > 1
+            && state.filelen == filelen
+            && state.ranges.len() == count
+            && part_path.metadata().map(|metadata| metadata.len() == filelen).unwrap_or(false)
+    });
+    if resumed.is_none() && ranges_file.exists() {
+        //the preallocated partial file has gaps, it is no prefix a sequential upload could continue
+        if part_path.exists() {
+            fs::remove_file(&part_path)?;
+        }
+        fs::remove_file(&ranges_file)?;
+    }
+    if count <= 1 {
+        return Ok(Vec::new());
+    }
+    let state = match resumed {
+        Some(state) => state,
+        None => {
+            if is_continue && !part_path.exists() && full_path.is_file() {
+                //let the finished file be compared first
+                return Ok(Vec::new());
+            }
+            fs::create_dir_all(full_path.parent().expect("no parent for full_path"))?;
+            let file = File::create(&part_path)?;
+            file.set_len(filelen)?;
+            let state = RangesState::new(filelen, 0, 0, count);
+            write_ranges(&ranges_file, &state)?;
+            state
+        }
+    };
+    let mut ranges: Vec<UploadRange> = Vec::new();
+    let mut spans: Vec<(u64, Digest)> = Vec::new();
+    for range in state.ranges {
+        let mut crc: u64 = 0;
+        if range.done > 0 {
+            let digest = digest_file_range(&part_path, range.start, range.done)?;
+            crc = digest.finalize();
+            spans.push((range.start, digest));
+        }
+        ranges.push(UploadRange {
+            start: range.start,
+            end: range.end,
+            done: range.done,
+            crc: crc,
+        });
+    }
+    reset_upload_digest(full_path, spans);
+    Ok(ranges)
+}
+
+fn record_upload_range(full_path:&Path, offset:u64, len:u64) -> Result<(), std::io::Error> {
+    //a sequential upload has no ranges file
+    let ranges_file = ranges_path(full_path);
+    let _guard = RANGES_LOCK.lock().expect("ranges lock poisoned");
+    if let Some(mut state) = read_ranges(&ranges_file) {
+        state.record(offset, len);
+        write_ranges(&ranges_file, &state)?;
+    }
+    Ok(())
+}
+
+//a transfer that was abandoned or failed never reaches End, its spans are dropped once nothing was added to them for this long.
+//a transfer that comes back later has End read the file for them instead
+const DIGEST_SPANS_EXPIRY: Duration = Duration::from_secs(600);
+
+struct DigestSpans {
+    spans: BTreeMap<u64, Digest>, //by the offset each span of hashed bytes starts at
+    touched: Instant,
+}
+impl DigestSpans {
+    fn new(spans: BTreeMap<u64, Digest>) -> DigestSpans {
+        DigestSpans {
+            spans: spans,
+            touched: Instant::now(),
+        }
+    }
+}
+
+fn expire_digest_spans<K: Ord>(digests:&mut BTreeMap<K, DigestSpans>) {
+    digests.retain(|_, digest_spans| digest_spans.touched.elapsed() < DIGEST_SPANS_EXPIRY);
+}
+
+//crc of what each upload has written to its partial file, kept as the chunks arrive so End need not read the file again.
+//a ranged upload has a span per range. lost when the server restarts, End then reads the file
+static UPLOAD_DIGESTS: Mutex<BTreeMap<PathBuf, DigestSpans>> = Mutex::new(BTreeMap::new());
+
+fn reset_upload_digest(full_path:&Path, spans:Vec<(u64, Digest)>) {
+    //forgets what was hashed for an upload, keeping only spans just read back from its partial file
+    let mut upload_digests = UPLOAD_DIGESTS.lock().expect("upload digests lock poisoned");
+    expire_digest_spans(&mut upload_digests);
+    upload_digests.remove(full_path);
+    if !spans.is_empty() {
+        upload_digests.insert(full_path.to_path_buf(), DigestSpans::new(spans.into_iter().collect()));
+    }
+}
+
+fn upload_digest_at(full_path:&Path, offset:u64) -> (u64, Digest) {
+    //the span that bytes written at offset carry on from, or a new one starting there
+    let upload_digests = UPLOAD_DIGESTS.lock().expect("upload digests lock poisoned");
+    if let Some(digest_spans) = upload_digests.get(full_path)
+        && let Some((start, digest)) = digest_spans.spans.iter().find(|(start, digest)| **start + digest.get_amount() == offset) {
+        return (*start, *digest);
+    }
+    (offset, Digest::new(Crc64Nvme))
+}
+
+fn record_upload_digest(full_path:&Path, start:u64, digest:Digest) {
+    //spans the new bytes were written over may not match the file any more, so they are dropped
+    let end = start + digest.get_amount();
+    let mut upload_digests = UPLOAD_DIGESTS.lock().expect("upload digests lock poisoned");
+    expire_digest_spans(&mut upload_digests);
+    let digest_spans = upload_digests.entry(full_path.to_path_buf()).or_insert_with(|| DigestSpans::new(BTreeMap::new()));
+    digest_spans.touched = Instant::now();
+    let spans = &mut digest_spans.spans;
+    spans.retain(|span_start, span_digest| *span_start + span_digest.get_amount() <= start || *span_start >= end);
+    spans.insert(start, digest);
+}
+
+fn take_upload_crc(full_path:&Path, filelen:u64) -> Option<u64> {
+    //crc of the whole partial file, if the spans hashed cover it from end to end
+    let spans = UPLOAD_DIGESTS.lock().expect("upload digests lock poisoned").remove(full_path)?.spans;
+    let mut digest = Digest::new(Crc64Nvme);
+    for (start, span_digest) in spans {
+        if start != digest.get_amount() {
+            return None;
+        }
+        digest.combine(&span_digest);
+    }
+    if digest.get_amount() != filelen {
+        return None;
+    }
+    Some(digest.finalize())
+}
+
+type FileVersion = (PathBuf, u64, u64); //path, size and mtime of a file
+
+#[derive(Default)]
+struct HashCache {
+    capacity: usize, //files remembered, 0 for no cache
+    crcs: Mutex<(HashMap<FileVersion, u64>, VecDeque<FileVersion>)>, //and the order they were added in, the oldest goes first
+}
+impl HashCache {
+    fn new(capacity: usize) -> HashCache {
+        HashCache {
+            capacity: capacity,
+            ..Default::default()
+        }
+    }
+
+    fn get(&self, version: &FileVersion) -> Option<u64> {
+        self.crcs.lock().expect("hash cache lock poisoned").0.get(version).copied()
+    }
+
+    fn insert(&self, version: FileVersion, crc: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut crcs = self.crcs.lock().expect("hash cache lock poisoned");
+        let (by_version, order) = &mut *crcs;
+        if by_version.insert(version.clone(), crc).is_none() {
+            order.push_back(version);
+            if order.len() > self.capacity && let Some(oldest) = order.pop_front() {
+                by_version.remove(&oldest);
+            }
+        }
+    }
+}
+
+//connections being handled. once max are open the accept loop waits for one to close, so clients cannot use up threads and file handles
+struct ConnectionLimit {
+    max: usize,
+    open: Mutex<usize>,
+    closed: Condvar,
+}
+impl ConnectionLimit {
+    fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            max: max.max(1),
+            open: Mutex::new(0),
+            closed: Condvar::new(),
+        }
+    }
+
+    fn acquire(self: &Arc<Self>) -> ConnectionSlot {
+        let mut open = self.open.lock().expect("connection limit lock poisoned");
+        if *open >= self.max {
+            warn!("{} connections open, waiting for one to close", *open);
+        }
+        while *open >= self.max {
+            open = self.closed.wait(open).expect("connection limit lock poisoned");
+        }
+        *open += 1;
+        ConnectionSlot {
+            limit: Arc::clone(self),
+        }
+    }
+}
+
+struct ConnectionSlot {
+    limit: Arc<ConnectionLimit>,
+}
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        *self.limit.open.lock().expect("connection limit lock poisoned") -= 1;
+        self.limit.closed.notify_one();
+    }
+}
+
+//crc of what downloads have sent of each version of a file. End combines them and only reads the file for what no span covers,
+//as for the start of a resumed download sent before the server restarted
+static DOWNLOAD_DIGESTS: Mutex<BTreeMap<FileVersion, DigestSpans>> = Mutex::new(BTreeMap::new());
+
+fn record_download_digest(version:&FileVersion, start:u64, digest:Digest) {
+    //bytes sent from start. downloads of the same version carry on from the span that ends where their bytes start
+    let mut download_digests = DOWNLOAD_DIGESTS.lock().expect("download digests lock poisoned");
+    //other versions of the file are gone
+    download_digests.retain(|other, _| other.0 != version.0 || other == version);
+    expire_digest_spans(&mut download_digests);
+    let digest_spans = download_digests.entry(version.clone()).or_insert_with(|| DigestSpans::new(BTreeMap::new()));
+    digest_spans.touched = Instant::now();
+    let spans = &mut digest_spans.spans;
+    let (span_start, span_digest) = match spans.iter().find(|(span_start, span_digest)| **span_start + span_digest.get_amount() == start) {
+        Some((span_start, span_digest)) => {
+            let mut extended = *span_digest;
+            extended.combine(&digest);
+            (*span_start, extended)
+        }
+        None => (start, digest),
+    };
+    //the same bytes sent twice leave the longer span
+    if spans.get(&span_start).is_none_or(|existing| existing.get_amount() < span_digest.get_amount()) {
+        spans.insert(span_start, span_digest);
+    }
+}
+
+fn is_sendfile(hash_cache:&HashCache, version:&FileVersion, compression:Compression) -> bool {
+    //sendfile only for uncompressed chunks of a file whose crc End will not need the bytes for
+    compression == Compression::None && hash_cache.get(version).is_some()
+}
+
+fn download_crc(full_path:&Path, version:&FileVersion, hash_cache:&HashCache) -> Result<u64, std::io::Error> {
+    //crc of the whole file from the cache, or from the spans sent and reading what they miss
+    if let Some(crc) = hash_cache.get(version) {
+        return Ok(crc);
+    }
+    let spans = DOWNLOAD_DIGESTS.lock().expect("download digests lock poisoned").remove(version).map(|digest_spans| digest_spans.spans).unwrap_or_default();
+    let filelen = version.1;
+    let mut digest = Digest::new(Crc64Nvme);
+    while digest.get_amount() < filelen {
+        let pos = digest.get_amount();
+        match spans.get(&pos) {
+            Some(span_digest) if span_digest.get_amount() > 0 && pos + span_digest.get_amount() <= filelen => {
+                digest.combine(span_digest);
+            }
+            _ => {
+                let next_start = spans.range(pos + 1..).map(|(span_start, _)| *span_start).next().unwrap_or(filelen).min(filelen);
+                debug!("Reading bytes {}-{} of {} for its crc", pos, next_start, full_path.to_string_lossy());
+                digest.combine(&digest_file_range(full_path, pos, next_start - pos)?);
+            }
+        }
+    }
+    let crc = digest.finalize();
+    hash_cache.insert(version.clone(), crc);
+    Ok(crc)
+}
+
+fn serve_subscription(stream:&mut TcpStream, full_path:&Path, settle:Duration) -> Result<(), Box<dyn Error>> {
+    //sends an event frame for each settled change below full_path until the client goes away, and a heartbeat when there is nothing to send
+    let (tx, rx) = mpsc::channel::<notify::Result<notify::Event>>();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(full_path, RecursiveMode::Recursive)?;
+    let subscribed = SubscribeServerEvent {
+        error_msg: None,
+        change_type: ChangeType::Subscribed.to_u8(),
+        path: String::new(),
+        entry_type: EntryType::Dir.to_u8(),
+        size: 0,
+        mtime: 0,
+    };
+    write_frame(stream, &wincode::serialize(&subscribed)?)?;
+    let mut last_sent = Instant::now();
+
+    let mut pending = PendingPaths::new(settle);
+    let mut created: HashSet<PathBuf> = HashSet::new();
+    loop {
+        match rx.recv_timeout(Duration::from_millis(250)) {
+            Ok(Ok(event)) => {
+                match event.kind {
+                    EventKind::Access(_) => continue,
+                    EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_)) => created.extend(event.paths.iter().cloned()),
+                    _ => {}
+                }
+                for path in event.paths {
+                    //unfinished uploads are reported once they are renamed into place
+                    if path != full_path && !is_partial_path(&path) {
+                        pending.touch(path);
+                    }
+                }
+            }
+            Ok(Err(e)) => {
+                error!("watch error: {}", e);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err("File watcher stopped")?
+            }
+        }
+        for path in pending.take_settled() {
+            let Some(rel) = rel_string(&path, full_path) else {
+                continue;
+            };
+            let mut subscribe_server_event = SubscribeServerEvent {
+                error_msg: None,
+                change_type: ChangeType::Deleted.to_u8(),
+                path: rel,
+                entry_type: EntryType::Other.to_u8(),
+                size: 0,
+                mtime: 0,
+            };
+            let is_created = created.remove(&path);
+            if let Ok(metadata) = fs::symlink_metadata(&path) {
+                subscribe_server_event.change_type = if is_created {ChangeType::Created.to_u8()} else {ChangeType::Modified.to_u8()};
+                subscribe_server_event.entry_type = EntryType::from_file_type(metadata.file_type()).to_u8();
+                subscribe_server_event.size = if metadata.is_dir() {0} else {metadata.len()};
+                subscribe_server_event.mtime = systemtime_to_unixtimestamp(metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+            }
+            if let Err(e) = write_frame(stream, &wincode::serialize(&subscribe_server_event)?) {
+                info!("Subscriber went away: {}", e);
+                return Ok(());
+            }
+            last_sent = Instant::now();
+        }
+        if last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+            let heartbeat = SubscribeServerEvent {
+                error_msg: None,
+                change_type: ChangeType::Heartbeat.to_u8(),
+                path: String::new(),
+                entry_type: EntryType::Other.to_u8(),
+                size: 0,
+                mtime: 0,
+            };
+            if let Err(e) = write_frame(stream, &wincode::serialize(&heartbeat)?) {
+                info!("Subscriber went away: {}", e);
+                return Ok(());
+            }
+            last_sent = Instant::now();
+        }
+    }
+}
+
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Option<Instant>,
+}
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf:&mut [u8]) -> Result<usize, std::io::Error> {
+        if let Some(deadline) = self.deadline {
+            //each read may only wait for what is left, so a client trickling bytes cannot hold the connection open
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "request was not received before the deadline"))?;
+            }
+            self.stream.set_read_timeout(Some(remaining))?;
+        }
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+fn read_request(stream:&TcpStream, buffer:&mut Vec<u8>) -> Result<usize, std::io::Error> {
+    //reads a request whole, except the file bytes of an upload chunk or delta which stay on the stream to be written straight to the file
+    //the whole request has to arrive within the read timeout, not only each read
+    let io_timeout = stream.read_timeout()?;
+    let mut reader = DeadlineReader {
+        stream: stream,
+        deadline: io_timeout.map(|io_timeout| Instant::now() + io_timeout),
+    };
+    (&mut reader).take(6).read_to_end(buffer)?;
+    if buffer.len() == 6 && buffer[4] == 1 && (buffer[5] == FileCopyStep::Transfer.to_u8() || buffer[5] == FileCopyStep::Delta.to_u8()) {
+        (&mut reader).take(8).read_to_end(buffer)?;
+        if buffer.len() == 14 {
+            let header_len:[u8; 8] = buffer[6..6+8].try_into().expect("Could not convert header_len bytes to fixed length");
+            (&mut reader).take(u64::from_le_bytes(header_len)).read_to_end(buffer)?;
+        }
+    } else {
+        reader.read_to_end(buffer)?;
+    }
+    stream.set_read_timeout(io_timeout)?;
+    Ok(buffer.len())
+}
+
+fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>, hash_cache:Arc<HashCache>) -> Result<(), Box<dyn Error>> {
     // A buffer to hold the incoming data
     let mut buffer = Vec::new();
 
-    match stream.read_to_end(&mut buffer) {
+    match read_request(&stream, &mut buffer) {
         Ok(n) if n >= 6 => {
             //expected chunk header: signature: 4 bytes + step 1 byte,
             let signature:[u8; 4] = buffer[0..4].try_into().expect("buffer size is not 4 bytes");
@@ -31,7 +665,7 @@ fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>) -> Result<(),
             let is_upload = buffer[4];
             let step = buffer[5];
             // println!("is_upload: {}, step: {}", is_upload, step);
-            let step = FileCopyStep::from_u8(step).expect(&format!("unexpected step value: {}", step));
+            let step = FileCopyStep::from_u8(step).unwrap_or_else(|| panic!("unexpected step value: {}", step));
             if is_upload == 0 {
                 //is download operation
                 // println!("step: {:?}", step);
@@ -39,20 +673,66 @@ fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>) -> Result<(),
                     let stream_bytes = &buffer[6..];
                     let download_client_initialise:DownloadClientInitalise = wincode::deserialize(stream_bytes).expect("Could not deserialize bytes to DownloadClientInitalise");
                     debug!("{:#?}", download_client_initialise);
-                    let full_path: PathBuf = get_full_path(root_path, download_client_initialise.serverside_path);
+                    let full_path: PathBuf = get_full_path(root_path.clone(), download_client_initialise.serverside_path);
                     let download_server_initialise: DownloadServerInitalise;
-                    if !full_path.exists() {
+                    if !is_within_root(&root_path, &full_path) {
+                        download_server_initialise = DownloadServerInitalise {
+                            error_msg: Some(format!("Path is outside of the server root: {}", full_path.to_string_lossy())),
+                            filelen: 0,
+                            mtime: 0,
+                            crc: None,
+                            prefix_matches: false,
+                            ranges_match: Vec::new(),
+                            is_dir: false,
+                            dir_files: Vec::new(),
+                            dir_dirs: Vec::new(),
+                        }
+                    } else if !full_path.exists() {
                         download_server_initialise = DownloadServerInitalise {
                             error_msg: Some(format!("File does not exist on server: {}", full_path.to_string_lossy())),
                             filelen: 0,
                             mtime: 0,
-                            crc: 0,
+                            crc: None,
+                            prefix_matches: false,
+                            ranges_match: Vec::new(),
+                            is_dir: false,
+                            dir_files: Vec::new(),
+                            dir_dirs: Vec::new(),
+                        }
+                    } else if full_path.is_dir() {
+                        let mut errmsg: Option<String> = None;
+                        let mut dir_files: Vec<String> = Vec::new();
+                        let mut dir_dirs: Vec<String> = Vec::new();
+                        let mut entries: Vec<ListEntry> = Vec::new();
+                        if let Err(e) = list_dir(&full_path, "", 1, 0, false, false, &mut entries) {
+                            errmsg = Some(format!("Error reading directory {}: {}", full_path.to_string_lossy(), e));
+                        }
+                        for entry in entries {
+                            match EntryType::from_u8(entry.entry_type) {
+                                Some(EntryType::Dir) => dir_dirs.push(entry.path),
+                                Some(EntryType::File) => dir_files.push(entry.path),
+                                Some(EntryType::Symlink) if full_path.join(&entry.path).is_file() => dir_files.push(entry.path),
+                                _ => {}
+                            }
+                        }
+                        download_server_initialise = DownloadServerInitalise {
+                            error_msg: errmsg,
+                            filelen: 0,
+                            mtime: 0,
+                            crc: None,
+                            prefix_matches: false,
+                            ranges_match: Vec::new(),
+                            is_dir: true,
+                            dir_files: dir_files,
+                            dir_dirs: dir_dirs,
                         }
                     } else {
                         let mut errmsg: Option<String> = None;
                         let mut filelen: u64 = 0;
                         let mut mtime: u64 = 0;
-                        let mut crc: u64 = 0;
+                        let mut crc: Option<u64> = None;
+                        let mut prefix_matches = false;
+                        let mut ranges_match: Vec<bool> = Vec::new();
                         match full_path.metadata() {
                             Ok(serverside_path_metadata) => {
                                 filelen = serverside_path_metadata.len();
@@ -62,14 +742,31 @@ fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>) -> Result<(),
                                 errmsg = Some(format!("Error getting serverside_path metadata for {}: {}", full_path.to_string_lossy(), e));
                             }
                         }
+                        //the crc is not worked out here unless cached, the bytes go first and it follows with End
+                        let version: FileVersion = (full_path.clone(), filelen, mtime);
                         if errmsg.is_none() {
-                            match checksum_file(Crc64Nvme, &full_path.to_string_lossy(), None) {
-                                Ok(file_crc) => {
-                                    crc = file_crc;
-                                }
-                                Err(e) => {
-                                    errmsg = Some(format!("Error getting crc for {}: {}", full_path.to_string_lossy(), e));
+                            crc = hash_cache.get(&version);
+                        }
+                        let resume_from = download_client_initialise.resume_from;
+                        if errmsg.is_none() && resume_from > 0 && resume_from <= filelen
+                            && let Ok(digest) = digest_file_range(&full_path, 0, resume_from) {
+                            prefix_matches = digest.finalize() == download_client_initialise.prefix_crc;
+                            if prefix_matches {
+                                //the client has these bytes, End need not read them again
+                                record_download_digest(&version, 0, digest);
+                            }
+                        }
+                        if errmsg.is_none() {
+                            for range in &download_client_initialise.ranges {
+                                let mut range_matches = false;
+                                if range.done > 0 && range.start + range.done <= filelen
+                                    && let Ok(digest) = digest_file_range(&full_path, range.start, range.done) {
+                                    range_matches = digest.finalize() == range.crc;
+                                    if range_matches {
+                                        record_download_digest(&version, range.start, digest);
+                                    }
                                 }
+                                ranges_match.push(range_matches);
                             }
                         }
                         download_server_initialise = DownloadServerInitalise {
@@ -77,6 +774,11 @@ fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>) -> Result<(),
                             filelen: filelen,
                             mtime: mtime,
                             crc: crc,
+                            prefix_matches: prefix_matches,
+                            ranges_match: ranges_match,
+                            is_dir: false,
+                            dir_files: Vec::new(),
+                            dir_dirs: Vec::new(),
                         }
                     }
                     let serialized = wincode::serialize(&download_server_initialise)?;
@@ -87,36 +789,98 @@ fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>) -> Result<(),
                     let download_client_transfer:DownloadClientTransfer = wincode::deserialize(stream_bytes).expect("Could not deserialize bytes to DownloadClientTransfer");
                     let full_path: PathBuf = get_full_path(root_path, download_client_transfer.serverside_path);
                     let mut errmsg: Option<String> = None;
-                    let mut bytes: Vec<u8> = Vec::new();
+                    let mut nbytes: u64 = 0;
+                    let mut filelen: u64 = 0;
+                    let mut mtime: u64 = 0;
+                    let mut file = File::open(&full_path)?;
                     'fileop: {
-                        let mut file = File::open(full_path)?;
+                        //report what the file looks like now, the client restarts if it changed since Initialise
+                        if let Ok(metadata) = file.metadata() {
+                            filelen = metadata.len();
+                            mtime = systemtime_to_unixtimestamp(metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+                        }
                         if let Err(e) = file.seek(std::io::SeekFrom::Start(download_client_transfer.from_byte)) {
                             errmsg = Some(format!("Error seeking file: {}", e));
                             break 'fileop;
                         }
-                        let mut buffer = vec![0u8; download_client_transfer.chunk_size];
-                        match file.read(&mut buffer) {
-                            Ok(nbytes) => {
-                                if nbytes==0 {
-                                    errmsg = Some(format!("0 bytes read"));
-                                    break 'fileop;
-                                }
-                                bytes = buffer[..nbytes].to_vec();
-                            }
-                            Err(e) => {
-                                errmsg = Some(format!("Error reading file: {}", e));
-                                break 'fileop;
-                            }
+                        //the bytes are sent after the header, straight from the file
+                        nbytes = filelen.saturating_sub(download_client_transfer.from_byte).min(download_client_transfer.chunk_size as u64);
+                        if nbytes==0 {
+                            errmsg = Some("0 bytes read".to_string());
+                            break 'fileop;
                         }
                     }
+                    //an algorithm this server does not know is answered uncompressed
+                    let compression = Compression::from_u8(download_client_transfer.compression).unwrap_or(Compression::None);
                     let download_server_transfer = DownloadServerTransfer {
                         error_msg: errmsg,
+                        filelen: filelen,
+                        mtime: mtime,
+                        compression: compression.to_u8(),
                         //bytes: bytes
                     };
                     let serialized = wincode::serialize(&download_server_transfer)?;
                     let header_len: u64 = serialized.len() as u64;
-                    let package = [header_len.to_le_bytes().to_vec(), serialized, bytes].concat();
+                    let package = [header_len.to_le_bytes().to_vec(), serialized].concat();
                     stream.write_all(&package)?;
+                    if nbytes > 0 {
+                        //a file that shrank meanwhile sends fewer, the client asks again from where its bytes end
+                        let from_byte = download_client_transfer.from_byte;
+                        let version: FileVersion = (full_path, filelen, mtime);
+                        let digest = if is_sendfile(&hash_cache, &version, compression) {
+                            //the crc is already known, so the bytes go with sendfile and never pass through the server
+                            send_file_bytes(&mut file, &mut stream, nbytes)?;
+                            Digest::new(Crc64Nvme)
+                        } else {
+                            //otherwise End needs their crc. they are read once through the hash on the way out, as sendfile would have
+                            //them read a second time for it
+                            let mut reader = DigestReader {
+                                inner: &mut file,
+                                digest: Digest::new(Crc64Nvme),
+                            };
+                            send_bytes(&mut reader, &mut stream, nbytes, compression, download_client_transfer.compression_level)?;
+                            reader.digest
+                        };
+                        if digest.get_amount() > 0 {
+                            record_download_digest(&version, from_byte, digest);
+                        }
+                    }
+                } else if step == FileCopyStep::End {
+                    let stream_bytes = &buffer[6..];
+                    let download_client_end:DownloadClientEnd = wincode::deserialize(stream_bytes).expect("Could not deserialize bytes to DownloadClientEnd");
+                    let full_path: PathBuf = get_full_path(root_path, download_client_end.serverside_path);
+                    let mut errmsg: Option<String> = None;
+                    let mut filelen: u64 = 0;
+                    let mut mtime: u64 = 0;
+                    let mut crc: u64 = 0;
+                    match full_path.metadata() {
+                        Ok(metadata) => {
+                            filelen = metadata.len();
+                            mtime = systemtime_to_unixtimestamp(metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+                        }
+                        Err(e) => {
+                            errmsg = Some(format!("Error getting serverside_path metadata for {}: {}", full_path.to_string_lossy(), e));
+                        }
+                    }
+                    //a file that changed since the client's Initialise is reported as it is now, the client starts again
+                    if errmsg.is_none() && (filelen, mtime) == (download_client_end.filelen, download_client_end.mtime) {
+                        match download_crc(&full_path, &(full_path.clone(), filelen, mtime), &hash_cache) {
+                            Ok(file_crc) => {
+                                crc = file_crc;
+                            }
+                            Err(e) => {
+                                errmsg = Some(format!("Error getting crc for {}: {}", full_path.to_string_lossy(), e));
+                            }
+                        }
+                    }
+                    let download_server_end = DownloadServerEnd {
+                        error_msg: errmsg,
+                        filelen: filelen,
+                        mtime: mtime,
+                        crc: crc,
+                    };
+                    let serialized = wincode::serialize(&download_server_end)?;
+                    stream.write_all(&serialized)?;
                 }
             } else if is_upload == 1 {
                 //is upload operation
@@ -126,14 +890,40 @@ fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>) -> Result<(),
                     debug!("{:#?}", upload_client_initialise);
                     let full_path: PathBuf = get_full_path(root_path, upload_client_initialise.serverside_path);
                     let mut errmsg: Option<String> = None;
+                    let part_path: PathBuf = partial_path(&full_path);
                     let mut filelen: u64 = 0;
-                    if !upload_client_initialise.is_continue && full_path.exists() {
-                        if let Err(e) = fs::remove_file(&full_path) {
-                            errmsg = Some(format!("Error deleting existing file on server: {}", e));
+                    let mut prefix_crc: u64 = 0;
+                    let mut exists_complete = false;
+                    if !upload_client_initialise.is_continue && part_path.exists() && let Err(e) = fs::remove_file(&part_path) {
+                        errmsg = Some(format!("Error deleting partial file on server: {}", e));
+                    }
+                    let mut ranges: Vec<UploadRange> = Vec::new();
+                    if errmsg.is_none() {
+                        match prepare_upload_ranges(&full_path, upload_client_initialise.is_continue, upload_client_initialise.filelen, upload_client_initialise.ranges as usize) {
+                            Ok(upload_ranges) => {
+                                ranges = upload_ranges;
+                            }
+                            Err(e) => {
+                                errmsg = Some(format!("Error preparing ranged upload on server: {}", e));
+                            }
                         }
                     }
-                    if errmsg.is_none() && full_path.exists() {
-                        match full_path.metadata() {
+                    if !ranges.is_empty() {
+                        filelen = upload_client_initialise.filelen;
+                    }
+                    //resume from the partial file, or report the finished file so an identical one is not sent again
+                    let mut existing_path: Option<&Path> = None;
+                    let mut spans: Vec<(u64, Digest)> = Vec::new();
+                    if errmsg.is_none() && !ranges.is_empty() {
+                        //a ranged upload reports the progress of each range instead
+                    } else if errmsg.is_none() && part_path.exists() {
+                        existing_path = Some(&part_path);
+                    } else if errmsg.is_none() && (upload_client_initialise.is_continue || upload_client_initialise.delta) && full_path.is_file() {
+                        existing_path = Some(&full_path);
+                        exists_complete = true;
+                    }
+                    if let Some(existing_path) = existing_path {
+                        match existing_path.metadata() {
                             Ok(dest_metadata) => {
                                 filelen = dest_metadata.len();
                             }
@@ -141,10 +931,31 @@ fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>) -> Result<(),
                                 errmsg = Some(format!("Error getting metadata of file on server: {}", e));
                             }
                         }
+                        if errmsg.is_none() && filelen > 0 {
+                            match digest_file_range(existing_path, 0, filelen) {
+                                Ok(digest) => {
+                                    prefix_crc = digest.finalize();
+                                    if !exists_complete {
+                                        //the chunks that follow carry on the crc of the partial file
+                                        spans.push((0, digest));
+                                    }
+                                }
+                                Err(e) => {
+                                    errmsg = Some(format!("Error getting crc for {}: {}", existing_path.to_string_lossy(), e));
+                                }
+                            }
+                        }
+                    }
+                    if ranges.is_empty() {
+                        reset_upload_digest(&full_path, spans);
                     }
                     let upload_server_initialise: UploadServerInitalise = UploadServerInitalise {
                         error_msg: errmsg,
-                        filelen: filelen
+                        filelen: filelen,
+                        prefix_crc: prefix_crc,
+                        exists_complete: exists_complete,
+                        ranges: ranges,
+                        compression: Compression::from_u8(upload_client_initialise.compression).unwrap_or(Compression::None).to_u8(),
                     };
                     let serialized = wincode::serialize(&upload_server_initialise)?;
                     stream.write_all(&serialized)?;
@@ -153,36 +964,195 @@ fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>) -> Result<(),
                     let header_len = u64::from_le_bytes(header_len);
                     let byte_starting_pos = 14+header_len as usize;
                     let header_bytes = &buffer[14..byte_starting_pos];
-                    let stream_bytes = &buffer[byte_starting_pos..];
                     let upload_client_transfer:UploadClientTransfer = wincode::deserialize(header_bytes).expect("Could not deserialize bytes to UploadClientTransfer");
                     let full_path: PathBuf = get_full_path(root_path, upload_client_transfer.serverside_path);
                     // println!("full_path: {:?}", full_path);
-                    // println!("stream_bytes.len(): {}", stream_bytes.len());
-                    //write bytes to end of file
+                    //write bytes at the offset in the partial file. writing a chunk again is harmless, leaving a gap is not
+                    let part_path: PathBuf = partial_path(&full_path);
+                    let mut filelen: u64 = 0;
                     let mut errmsg: Option<String> = None;
                     if let Err(e) = fs::create_dir_all(full_path.parent().unwrap()) {
                         errmsg = Some(format!("Error creating dirs on server: {}", e));
                     };
-                    let mut bytes: Vec<u8> = Vec::new();
-                    if errmsg.is_none() {
-                        bytes = stream_bytes.into();
-                    }
                     if errmsg.is_none() {
-                        {
-                            match OpenOptions::new().write(true).append(true).create(true).open(&full_path) {
-                                Ok(mut file) => {
-                                    if let Err(e) = file.write_all(&bytes) {
-                                        errmsg = Some(format!("Error writing data to file on server: {}", e));
-                                    };
-                                }
+                        'fileop: {
+                            let mut file = match OpenOptions::new().write(true).create(true).truncate(false).open(&part_path) {
+                                Ok(file) => file,
                                 Err(e) => {
                                     errmsg = Some(format!("Error opening file for writing on server: {}", e));
+                                    break 'fileop;
+                                }
+                            };
+                            let offset = upload_client_transfer.offset;
+                            match file.metadata() {
+                                Ok(metadata) if offset > metadata.len() => {
+                                    errmsg = Some(format!("Chunk offset {} is past the end of the {} byte file on server", offset, metadata.len()));
+                                    break 'fileop;
+                                }
+                                Ok(_) => {}
+                                Err(e) => {
+                                    errmsg = Some(format!("Error getting metadata of file on server: {}", e));
+                                    break 'fileop;
+                                }
+                            }
+                            if let Err(e) = file.seek(std::io::SeekFrom::Start(offset)) {
+                                errmsg = Some(format!("Error seeking file: {}", e));
+                                break 'fileop;
+                            }
+                            //the bytes after the header go straight from the stream into the file
+                            let Some(compression) = Compression::from_u8(upload_client_transfer.compression) else {
+                                errmsg = Some(format!("Unknown compression: {}", upload_client_transfer.compression));
+                                break 'fileop;
+                            };
+                            let (span_start, digest) = upload_digest_at(&full_path, offset);
+                            let mut writer = DigestWriter {
+                                inner: &mut file,
+                                digest: digest,
+                            };
+                            let nbytes = match receive_bytes(&mut stream, &mut writer, compression) {
+                                Ok(nbytes) => nbytes,
+                                Err(e) => {
+                                    errmsg = Some(format!("Error writing data to file on server: {}", e));
+                                    break 'fileop;
+                                }
+                            };
+                            if nbytes > 0 {
+                                record_upload_digest(&full_path, span_start, writer.digest);
+                            }
+                            if let Err(e) = record_upload_range(&full_path, offset, nbytes) {
+                                errmsg = Some(format!("Error recording upload progress on server: {}", e));
+                                break 'fileop;
+                            }
+                            match file.metadata() {
+                                Ok(metadata) => {
+                                    filelen = metadata.len();
+                                }
+                                Err(e) => {
+                                    errmsg = Some(format!("Error getting metadata of file on server: {}", e));
                                 }
                             }
                         }
                     }
+                    if errmsg.is_some() {
+                        //read what is left of the chunk so the client gets the reply rather than a reset connection
+                        let _ = copy_bytes(&mut stream, &mut std::io::sink(), u64::MAX);
+                    }
                     let upload_server_transfer = UploadServerTransfer {
-                        error_msg: errmsg
+                        error_msg: errmsg,
+                        filelen: filelen,
+                    };
+                    let serialized = wincode::serialize(&upload_server_transfer)?;
+                    stream.write_all(&serialized)?;
+                } else if step == FileCopyStep::Signatures {
+                    let stream_bytes = &buffer[6..];
+                    let upload_client_signatures:UploadClientSignatures = wincode::deserialize(stream_bytes).expect("Could not deserialize bytes to UploadClientSignatures");
+                    let full_path: PathBuf = get_full_path(root_path.clone(), upload_client_signatures.serverside_path);
+                    let mut errmsg: Option<String> = None;
+                    let mut filelen: u64 = 0;
+                    let mut mtime: u64 = 0;
+                    let mut blocks: Vec<BlockSignature> = Vec::new();
+                    if !is_within_root(&root_path, &full_path) {
+                        errmsg = Some(format!("Path is outside of the server root: {}", full_path.to_string_lossy()));
+                    } else {
+                        match full_path.metadata() {
+                            Ok(metadata) => {
+                                filelen = metadata.len();
+                                mtime = systemtime_to_unixtimestamp(metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+                            }
+                            Err(e) => {
+                                errmsg = Some(format!("Error getting metadata of file on server: {}", e));
+                            }
+                        }
+                    }
+                    if errmsg.is_none() {
+                        match block_signatures(&full_path, upload_client_signatures.block_size) {
+                            Ok(file_blocks) => {
+                                blocks = file_blocks;
+                            }
+                            Err(e) => {
+                                errmsg = Some(format!("Error getting block signatures for {}: {}", full_path.to_string_lossy(), e));
+                            }
+                        }
+                    }
+                    let upload_server_signatures = UploadServerSignatures {
+                        error_msg: errmsg,
+                        filelen: filelen,
+                        mtime: mtime,
+                        blocks: blocks,
+                    };
+                    let serialized = wincode::serialize(&upload_server_signatures)?;
+                    stream.write_all(&serialized)?;
+                } else if step == FileCopyStep::Delta {
+                    let header_len:[u8; 8] = buffer[6..6+8].try_into().expect("Could not convert header_len bytes to fixed length");
+                    let header_len = u64::from_le_bytes(header_len);
+                    let header_bytes = &buffer[14..14+header_len as usize];
+                    let upload_client_delta:UploadClientDelta = wincode::deserialize(header_bytes).expect("Could not deserialize bytes to UploadClientDelta");
+                    let full_path: PathBuf = get_full_path(root_path.clone(), upload_client_delta.serverside_path);
+                    //rebuild the file into a new partial file from the instructions and the file it replaces
+                    let part_path: PathBuf = partial_path(&full_path);
+                    let mut filelen: u64 = 0;
+                    let mut errmsg: Option<String> = None;
+                    'fileop: {
+                        if !is_within_root(&root_path, &full_path) {
+                            errmsg = Some(format!("Path is outside of the server root: {}", full_path.to_string_lossy()));
+                            break 'fileop;
+                        }
+                        let mut basis = match File::open(&full_path) {
+                            Ok(file) => file,
+                            Err(e) => {
+                                errmsg = Some(format!("Error opening file on server: {}", e));
+                                break 'fileop;
+                            }
+                        };
+                        match basis.metadata() {
+                            Ok(metadata) => {
+                                let basis_len_mtime = (metadata.len(), systemtime_to_unixtimestamp(metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH)));
+                                if basis_len_mtime != (upload_client_delta.basis_len, upload_client_delta.basis_mtime) {
+                                    errmsg = Some(format!("File {} changed on server since its block signatures were sent", full_path.to_string_lossy()));
+                                    break 'fileop;
+                                }
+                            }
+                            Err(e) => {
+                                errmsg = Some(format!("Error getting metadata of file on server: {}", e));
+                                break 'fileop;
+                            }
+                        }
+                        reset_upload_digest(&full_path, Vec::new());
+                        let part = match File::create(&part_path) {
+                            Ok(file) => file,
+                            Err(e) => {
+                                errmsg = Some(format!("Error opening file for writing on server: {}", e));
+                                break 'fileop;
+                            }
+                        };
+                        let mut writer = DigestWriter {
+                            inner: BufWriter::with_capacity(STREAM_BUFFER_SIZE, part),
+                            digest: Digest::new(Crc64Nvme),
+                        };
+                        match apply_delta(&mut stream, &mut basis, &mut writer, upload_client_delta.block_size as u64, upload_client_delta.basis_len) {
+                            Ok(nbytes) => {
+                                filelen = nbytes;
+                            }
+                            Err(e) => {
+                                errmsg = Some(format!("Error rebuilding file from delta on server: {}", e));
+                                break 'fileop;
+                            }
+                        }
+                        if let Err(e) = writer.flush() {
+                            errmsg = Some(format!("Error writing data to file on server: {}", e));
+                            break 'fileop;
+                        }
+                        if filelen > 0 {
+                            record_upload_digest(&full_path, 0, writer.digest);
+                        }
+                    }
+                    if errmsg.is_some() {
+                        //read what is left of the delta so the client gets the reply rather than a reset connection
+                        let _ = copy_bytes(&mut stream, &mut std::io::sink(), u64::MAX);
+                    }
+                    let upload_server_transfer = UploadServerTransfer {
+                        error_msg: errmsg,
+                        filelen: filelen,
                     };
                     let serialized = wincode::serialize(&upload_server_transfer)?;
                     stream.write_all(&serialized)?;
@@ -190,26 +1160,44 @@ fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>) -> Result<(),
                     let stream_bytes = &buffer[6..];
                     let upload_client_end:UploadClientEnd = wincode::deserialize(stream_bytes).expect("Could not deserialize bytes to UploadClientEnd");
                     let full_path: PathBuf = get_full_path(root_path, upload_client_end.serverside_path);
+                    let part_path: PathBuf = partial_path(&full_path);
                     let mut errmsg: Option<String> = None;
-                    if !full_path.exists() {
-                        errmsg = Some(format!("File {} does not exist on server.", full_path.to_string_lossy()));
+                    let mut is_done = false;
+                    if !part_path.exists() {
+                        //an End sent again after its reply was lost finds the file already renamed into place
+                        is_done = full_path.is_file() && checksum_file(Crc64Nvme, &full_path.to_string_lossy(), None).map(|file_crc| file_crc == upload_client_end.crc).unwrap_or(false);
+                        if !is_done {
+                            errmsg = Some(format!("File {} does not exist on server.", part_path.to_string_lossy()));
+                        }
                     }
-                    if errmsg.is_none() {
-                        match checksum_file(Crc64Nvme, &full_path.to_string_lossy(), None) {
+                    if errmsg.is_none() && !is_done {
+                        //the crc kept as the chunks were written, the file is only read again when that does not cover all of it
+                        let part_len = part_path.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+                        let file_crc = match take_upload_crc(&full_path, part_len) {
+                            Some(file_crc) => Ok(file_crc),
+                            None => {
+                                debug!("Reading {} again for its crc", part_path.to_string_lossy());
+                                checksum_file(Crc64Nvme, &part_path.to_string_lossy(), None)
+                            }
+                        };
+                        match file_crc {
                             Ok(file_crc) => {
                                 if file_crc != upload_client_end.crc {
                                     errmsg = Some(format!("CRC does not match for file {}", full_path.to_string_lossy()));
+                                    //a bad partial file would only be resumed again
+                                    let _ = fs::remove_file(&part_path);
+                                    let _ = fs::remove_file(ranges_path(&full_path));
                                 };
                             }
                             Err(e) => {
-                                errmsg = Some(format!("Error getting crc for {}: {}", full_path.to_string_lossy(), e));
+                                errmsg = Some(format!("Error getting crc for {}: {}", part_path.to_string_lossy(), e));
                             }
                         }
                     }
-                    if errmsg.is_none() {
+                    if errmsg.is_none() && !is_done {
                         let mtime = unixtimestamp_to_systemtime(upload_client_end.mtime);
                         {
-                            match OpenOptions::new().write(true).open(full_path) {
+                            match OpenOptions::new().write(true).open(&part_path) {
                                 Ok(file) => {
                                     let times = FileTimes::new()
                                         .set_modified(mtime);
@@ -223,6 +1211,14 @@ fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>) -> Result<(),
                             }
                         }
                     }
+                    if errmsg.is_none() && !is_done {
+                        //only now does the file appear under its real name
+                        if let Err(e) = fs::rename(&part_path, &full_path) {
+                            errmsg = Some(format!("Could not rename partial file into place on server: {}", e));
+                        } else if let Err(e) = fs::remove_file(ranges_path(&full_path)) && e.kind() != std::io::ErrorKind::NotFound {
+                            warn!("Could not remove ranges file on server: {}", e);
+                        }
+                    }
                     let upload_server_end = UploadServerEnd {
                         error_msg: errmsg
                     };
@@ -235,27 +1231,260 @@ fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>) -> Result<(),
                     let stream_bytes = &buffer[6..];
                     let delete_client_initialise:DeleteClientInitalise = wincode::deserialize(stream_bytes).expect("Could not deserialize bytes to DeleteClientInitalise");
                     debug!("{:#?}", delete_client_initialise);
-                    let full_path: PathBuf = get_full_path(root_path, delete_client_initialise.serverside_path);
+                    let serverside_path = delete_client_initialise.serverside_path.trim_end_matches(['/', '\\']).to_string();
+                    let full_path: PathBuf = get_full_path(root_path.clone(), delete_client_initialise.serverside_path);
                     let mut errmsg: Option<String> = None;
-                    if full_path.exists() {
-                        if full_path.is_dir() {
-                            if let Err(e) = fs::remove_dir(&full_path) {
-                                errmsg = Some(format!("Error deleting existing directory on server: {}", e));
+                    let mut removed: Vec<String> = Vec::new();
+                    'deleteop: {
+                        if !is_within_root(&root_path, &full_path) || is_root(&root_path, &full_path) {
+                            errmsg = Some(format!("Path is outside of, or is, the server root: {}", full_path.to_string_lossy()));
+                            break 'deleteop;
+                        }
+                        let metadata = match fs::symlink_metadata(&full_path) {
+                            Ok(metadata) => metadata,
+                            Err(_) => {
+                                errmsg = Some(format!("Path does not exist on server: {}", full_path.to_string_lossy()));
+                                break 'deleteop;
+                            }
+                        };
+                        if metadata.is_dir() {
+                            //unfinished uploads count too, so their partial files are never removed unasked
+                            let mut entries: Vec<ListEntry> = Vec::new();
+                            if let Err(e) = list_dir(&full_path, "", 1, 0, false, true, &mut entries) {
+                                errmsg = Some(format!("Error reading directory {}: {}", full_path.to_string_lossy(), e));
+                                break 'deleteop;
+                            }
+                            if !entries.is_empty() && !delete_client_initialise.recursive {
+                                errmsg = Some(format!("Directory is not empty on server, use a recursive delete: {}", full_path.to_string_lossy()));
+                                break 'deleteop;
+                            }
+                            if !entries.is_empty() && !delete_client_initialise.confirm && !delete_client_initialise.dry_run {
+                                errmsg = Some(format!("Refusing to recursively delete {} without confirmation", full_path.to_string_lossy()));
+                                break 'deleteop;
+                            }
+                            //contents before the directories containing them
+                            for entry in entries.iter().rev() {
+                                removed.push(format!("{}/{}", serverside_path, entry.path));
+                            }
+                            removed.push(serverside_path.clone());
+                            if !delete_client_initialise.dry_run {
+                                //remove_dir fails on a directory that filled up since it was listed
+                                let result = if delete_client_initialise.recursive {fs::remove_dir_all(&full_path)} else {fs::remove_dir(&full_path)};
+                                if let Err(e) = result {
+                                    errmsg = Some(format!("Error deleting existing directory on server: {}", e));
+                                }
                             }
                         } else {
-                            if let Err(e) = fs::remove_file(&full_path) {
+                            removed.push(serverside_path.clone());
+                            if !delete_client_initialise.dry_run && let Err(e) = fs::remove_file(&full_path) {
                                 errmsg = Some(format!("Error deleting existing file on server: {}", e));
                             }
                         }
-                    } else {
-                        errmsg = Some(format!("Path does not exist on server: {}", full_path.to_string_lossy()));
                     }
                     let delete_server_response = DeleteServerResponse {
                         error_msg: errmsg,
+                        removed: removed,
                     };
                     let serialized = wincode::serialize(&delete_server_response)?;
                     stream.write_all(&serialized)?;
                 }
+            } else if is_upload == 3 {
+                //is list operation
+                if step == FileCopyStep::Initialise {
+                    let stream_bytes = &buffer[6..];
+                    let list_client_initialise:ListClientInitalise = wincode::deserialize(stream_bytes).expect("Could not deserialize bytes to ListClientInitalise");
+                    debug!("{:#?}", list_client_initialise);
+                    let full_path: PathBuf = get_full_path(root_path.clone(), list_client_initialise.serverside_path);
+                    let mut errmsg: Option<String> = None;
+                    let mut entries: Vec<ListEntry> = Vec::new();
+                    if !is_within_root(&root_path, &full_path) {
+                        errmsg = Some(format!("Path is outside of the server root: {}", full_path.to_string_lossy()));
+                    } else {
+                        match fs::symlink_metadata(&full_path) {
+                            Ok(metadata) if metadata.is_dir() => {
+                                let max_depth = if list_client_initialise.recursive {list_client_initialise.max_depth} else {1};
+                                if let Err(e) = list_dir(&full_path, "", 1, max_depth, list_client_initialise.include_crc, false, &mut entries) {
+                                    errmsg = Some(format!("Error reading directory {}: {}", full_path.to_string_lossy(), e));
+                                }
+                            }
+                            Ok(metadata) => {
+                                let name = full_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                                entries.push(list_entry(&full_path, name, EntryType::from_file_type(metadata.file_type()), list_client_initialise.include_crc));
+                            }
+                            Err(_) => {
+                                errmsg = Some(format!("Path does not exist on server: {}", full_path.to_string_lossy()));
+                            }
+                        }
+                    }
+                    let list_server_response = ListServerResponse {
+                        error_msg: errmsg,
+                        entries: entries,
+                    };
+                    let serialized = wincode::serialize(&list_server_response)?;
+                    stream.write_all(&serialized)?;
+                }
+            } else if is_upload == 4 {
+                //is stat operation
+                if step == FileCopyStep::Initialise {
+                    let stream_bytes = &buffer[6..];
+                    let stat_client_initialise:StatClientInitalise = wincode::deserialize(stream_bytes).expect("Could not deserialize bytes to StatClientInitalise");
+                    debug!("{:#?}", stat_client_initialise);
+                    let full_path: PathBuf = get_full_path(root_path.clone(), stat_client_initialise.serverside_path);
+                    let mut stat_server_response = StatServerResponse {
+                        error_msg: None,
+                        exists: false,
+                        entry_type: EntryType::Other.to_u8(),
+                        size: 0,
+                        mtime: 0,
+                        permissions: 0,
+                        readonly: false,
+                        symlink_target: None,
+                        crc: None,
+                    };
+                    if !is_within_root(&root_path, &full_path) {
+                        stat_server_response.error_msg = Some(format!("Path is outside of the server root: {}", full_path.to_string_lossy()));
+                    } else {
+                        match fs::symlink_metadata(&full_path) {
+                            Ok(metadata) => {
+                                stat_server_response.exists = true;
+                                stat_server_response.entry_type = EntryType::from_file_type(metadata.file_type()).to_u8();
+                                if !metadata.is_dir() {
+                                    stat_server_response.size = metadata.len();
+                                }
+                                stat_server_response.mtime = systemtime_to_unixtimestamp(metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+                                stat_server_response.permissions = permissions_mode(&metadata);
+                                stat_server_response.readonly = metadata.permissions().readonly();
+                                if metadata.is_symlink() {
+                                    stat_server_response.symlink_target = fs::read_link(&full_path).ok().map(|target| target.to_string_lossy().to_string());
+                                }
+                                if stat_client_initialise.include_hash && metadata.is_file() {
+                                    match checksum_file(Crc64Nvme, &full_path.to_string_lossy(), None) {
+                                        Ok(file_crc) => {
+                                            stat_server_response.crc = Some(file_crc);
+                                        }
+                                        Err(e) => {
+                                            stat_server_response.error_msg = Some(format!("Error getting crc for {}: {}", full_path.to_string_lossy(), e));
+                                        }
+                                    }
+                                }
+                            }
+                            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                            Err(e) => {
+                                stat_server_response.error_msg = Some(format!("Error getting metadata for {}: {}", full_path.to_string_lossy(), e));
+                            }
+                        }
+                    }
+                    let serialized = wincode::serialize(&stat_server_response)?;
+                    stream.write_all(&serialized)?;
+                }
+            } else if is_upload == 5 || is_upload == 6 {
+                //is move or copy operation
+                if step == FileCopyStep::Initialise {
+                    let stream_bytes = &buffer[6..];
+                    let (serverside_from, serverside_to, overwrite_policy) = if is_upload == 5 {
+                        let move_client_initialise:MoveClientInitalise = wincode::deserialize(stream_bytes).expect("Could not deserialize bytes to MoveClientInitalise");
+                        debug!("{:#?}", move_client_initialise);
+                        (move_client_initialise.serverside_from, move_client_initialise.serverside_to, move_client_initialise.overwrite_policy)
+                    } else {
+                        let copy_client_initialise:CopyClientInitalise = wincode::deserialize(stream_bytes).expect("Could not deserialize bytes to CopyClientInitalise");
+                        debug!("{:#?}", copy_client_initialise);
+                        (copy_client_initialise.serverside_from, copy_client_initialise.serverside_to, copy_client_initialise.overwrite_policy)
+                    };
+                    let full_from: PathBuf = get_full_path(root_path.clone(), serverside_from);
+                    let full_to: PathBuf = get_full_path(root_path.clone(), serverside_to);
+                    let mut errmsg: Option<String> = None;
+                    let mut skipped = false;
+                    if !is_within_root(&root_path, &full_from) || !is_within_root(&root_path, &full_to) {
+                        errmsg = Some(format!("Path is outside of the server root: {} or {}", full_from.to_string_lossy(), full_to.to_string_lossy()));
+                    } else if is_root(&root_path, &full_from) || is_root(&root_path, &full_to) {
+                        errmsg = Some(format!("Refusing to move or copy to or from the server root: {} to {}", full_from.to_string_lossy(), full_to.to_string_lossy()));
+                    } else {
+                        match OverwritePolicy::from_u8(overwrite_policy) {
+                            Some(overwrite_policy) => {
+                                let result = if is_upload == 5 {
+                                    move_path(&full_from, &full_to, overwrite_policy)
+                                } else {
+                                    copy_path(&full_from, &full_to, overwrite_policy)
+                                };
+                                match result {
+                                    Ok(is_skipped) => skipped = is_skipped,
+                                    Err(e) => errmsg = Some(e),
+                                }
+                            }
+                            None => {
+                                errmsg = Some(format!("Unknown overwrite_policy value: {}", overwrite_policy));
+                            }
+                        }
+                    }
+                    let serialized = if is_upload == 5 {
+                        wincode::serialize(&MoveServerResponse {
+                            error_msg: errmsg,
+                            skipped: skipped,
+                        })?
+                    } else {
+                        wincode::serialize(&CopyServerResponse {
+                            error_msg: errmsg,
+                            skipped: skipped,
+                        })?
+                    };
+                    stream.write_all(&serialized)?;
+                }
+            } else if is_upload == 7 {
+                //is mkdir operation
+                if step == FileCopyStep::Initialise {
+                    let stream_bytes = &buffer[6..];
+                    let mkdir_client_initialise:MkdirClientInitalise = wincode::deserialize(stream_bytes).expect("Could not deserialize bytes to MkdirClientInitalise");
+                    debug!("{:#?}", mkdir_client_initialise);
+                    let full_path: PathBuf = get_full_path(root_path.clone(), mkdir_client_initialise.serverside_path);
+                    let mut errmsg: Option<String> = None;
+                    let mut created = false;
+                    if !is_within_root(&root_path, &full_path) {
+                        errmsg = Some(format!("Path is outside of the server root: {}", full_path.to_string_lossy()));
+                    } else if full_path.is_dir() {
+                        //already exists
+                    } else if full_path.exists() {
+                        errmsg = Some(format!("Path exists on server and is not a directory: {}", full_path.to_string_lossy()));
+                    } else {
+                        match fs::create_dir_all(&full_path) {
+                            Ok(()) => created = true,
+                            Err(e) => errmsg = Some(format!("Error creating dirs on server: {}", e)),
+                        }
+                    }
+                    let mkdir_server_response = MkdirServerResponse {
+                        error_msg: errmsg,
+                        created: created,
+                    };
+                    let serialized = wincode::serialize(&mkdir_server_response)?;
+                    stream.write_all(&serialized)?;
+                }
+            } else if is_upload == 8 {
+                //is subscribe operation, the connection stays open
+                if step == FileCopyStep::Initialise {
+                    let stream_bytes = &buffer[6..];
+                    let subscribe_client_initialise:SubscribeClientInitalise = wincode::deserialize(stream_bytes).expect("Could not deserialize bytes to SubscribeClientInitalise");
+                    debug!("{:#?}", subscribe_client_initialise);
+                    let full_path: PathBuf = get_full_path(root_path.clone(), subscribe_client_initialise.serverside_path);
+                    let mut errmsg: Option<String> = None;
+                    if !is_within_root(&root_path, &full_path) {
+                        errmsg = Some(format!("Path is outside of the server root: {}", full_path.to_string_lossy()));
+                    } else if !full_path.is_dir() {
+                        errmsg = Some(format!("Directory does not exist on server: {}", full_path.to_string_lossy()));
+                    }
+                    if errmsg.is_some() {
+                        let subscribe_server_event = SubscribeServerEvent {
+                            error_msg: errmsg,
+                            change_type: ChangeType::Subscribed.to_u8(),
+                            path: String::new(),
+                            entry_type: EntryType::Other.to_u8(),
+                            size: 0,
+                            mtime: 0,
+                        };
+                        write_frame(&mut stream, &wincode::serialize(&subscribe_server_event)?)?;
+                    } else {
+                        let settle = Duration::from_millis(subscribe_client_initialise.settle_ms.max(100));
+                        serve_subscription(&mut stream, &full_path, settle)?;
+                    }
+                }
             } else {
                 Err(format!("Unknown is_upload value: {}", is_upload))?;
             }
@@ -273,15 +1502,21 @@ fn handle_client(mut stream: TcpStream, root_path:Option<PathBuf>) -> Result<(),
     Ok(())
 }
 
-fn run_server(host:&str, port:&str, root_path:Option<PathBuf>) -> Result<(), std::io::Error> {
+fn run_server(host:&str, port:&str, root_path:Option<PathBuf>, io_timeout:Duration, hash_cache_entries:usize, max_connections:usize) -> Result<(), std::io::Error> {
     let address = format!("{}:{}", host, port);
     let listener = TcpListener::bind(&address)?;
     // let streams_in_progress: Arc<RwLock<HashMap<[u8; 16], StreamProgress>>> = Arc::new(RwLock::new(HashMap::new()));
 
+    let hash_cache = Arc::new(HashCache::new(hash_cache_entries));
+    if hash_cache_entries == 0 {
+        warn!("--hash-cache 0: download chunks are read through the server to hash them, never sent with sendfile");
+    }
+    let connection_limit = Arc::new(ConnectionLimit::new(max_connections));
+
     println!("TCP Server running on {}", address);
     println!("Listening for connections...");
 
-    // Accept connections and process them sequentially
+    // Accept connections, each handled on its own thread up to max_connections at once. subscriptions hold theirs open
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
@@ -292,12 +1527,19 @@ fn run_server(host:&str, port:&str, root_path:Option<PathBuf>) -> Result<(), std
                         // Handle the client in a new thread to allow for concurrent connections
                         // let streams_in_progress_clone = Arc::clone(&streams_in_progress);
                         let root_path_clone = root_path.clone();
-                        let handle = thread::spawn(move || {
-                            handle_client(stream, root_path_clone).expect("Error from handle_client");
-                        });
-                        if let Err(e) = handle.join() {
-                            error!("Error in handle_client: {:?}", e);
+                        let hash_cache = Arc::clone(&hash_cache);
+                        //a client that stops sending or reading only holds its thread until the timeout
+                        if let Err(e) = configure_stream(&stream, io_timeout, io_timeout) {
+                            error!("Could not configure connection: {}", e);
+                            continue;
                         }
+                        let connection_slot = connection_limit.acquire();
+                        thread::spawn(move || {
+                            if let Err(e) = handle_client(stream, root_path_clone, hash_cache) {
+                                error!("Error in handle_client: {}", e);
+                            }
+                            drop(connection_slot);
+                        });
                     }
                     Err(e) => {
                         panic!("!!! ERROR 1 {}", e)
@@ -314,28 +1556,140 @@ fn run_server(host:&str, port:&str, root_path:Option<PathBuf>) -> Result<(), std
 
 fn print_usage() {
     eprintln!("\nTCP App Usage:");
-    eprintln!("  Server: cargo run -- server HOST PORT --path root_path");
+    eprintln!("  Server: cargo run -- server HOST PORT --path root_path [--timeout SECS]");
+    eprintln!("          [--hash-cache N] (default 1024, remember the crc of N files by path, size and mtime, so downloading them again does not read them for it)");
+    eprintln!("          (only a file whose crc is remembered is sent with sendfile, --hash-cache 0 turns sendfile off)");
+    eprintln!("          [--max-connections N] (default 256, connections handled at once including subscriptions. more wait until one closes)");
     // cargo run server 127.0.0.1 52709 --path "/home/ray/temp"
     // cargo run server XXPA201LAP00072.local 52709 --path "C:\Users\hrag\temp"
     // cargo run server XXPA201LAP00072.local 52710 --path "C:\Users\hrag"
-    eprintln!("  Client: cargo run -- upload HOST PORT src_path_local dest_path_server");
+    eprintln!("  Client: cargo run -- upload HOST PORT src_path_local dest_path_server [--watch [--watch-delete] [--settle SECS]]");
+    eprintln!("          [--delta] (a file that differs from the one on the server is sent as only the blocks that changed)");
     // cargo run upload 127.0.0.1 52709 "./tests/Bremshley Treadmill Service Manual.pdf" "./large"
     // cargo run upload 127.0.0.1 52709 "/home/ray/Downloads/vulkansdk-linux-x86_64-1.4.328.1.tar.xz" "./large"
     // cargo run upload XXPA201LAP00072.local 52709 "./tests/Bremshley Treadmill Service Manual.pdf" "./large"
     // cargo run upload XXPA201LAP00072.local 52709 "c:\Users\hrag\Sync\onecard.txt" ""
     // cargo run upload XXPA201LAP00072.local 52710 "/home/ray/MEGA/Rays/Programming/LLM/EmailResponses/outtext_gemma.txt" "./Sync/Programming/LLM/EmailResponses" --overwrite
+    // cargo run upload XXPA201LAP00072.local 52710 "/home/ray/MEGA/Rays/Programming/LLM/EmailResponses" "./Sync/Programming/LLM" --watch --settle 5
     eprintln!("  Client: cargo run -- download HOST PORT src_path_server dest_path_local");
+    eprintln!("          (src_path_server may be a directory, which is downloaded recursively)");
+    eprintln!("          upload, download, sync and follow also take [--window N] [--max-restarts N] [--retries N]");
+    eprintln!("          (--window N chunk requests in flight, fewer when N chunks would take more than half the --timeout to arrive)");
+    eprintln!("          [--chunk-size BYTES] (fixed) or [--min-chunk-size BYTES] [--max-chunk-size BYTES] [--chunk-target SECS] (resized to take SECS each)");
+    eprintln!("          [--connect-timeout SECS] [--timeout SECS] (read/write, 0 for none)");
+    eprintln!("          [--connections N] (files larger than a chunk are sent as N byte ranges in parallel)");
+    eprintln!("          [--compress none|zstd|lz4] [--compress-level N] (zstd level, chunks that do not shrink go uncompressed)");
     // cargo run download 127.0.0.1 52709 "./large/Bremshley Treadmill Service Manual.pdf" "/home/ray/temp/rec"
     // cargo run download XXPA201LAP00072.local 52709 "./large/Bremshley Treadmill Service Manual.pdf" "C:\Users\hrag\temp\rec"
     // cargo run download XXPA201LAP00072.local 52710 "Sync/network/router.txt~" "/home/ray/MEGA/Rays/network" --overwrite
-    eprintln!("  Client: cargo run -- delete HOST PORT path_server");
+    eprintln!("  Client: cargo run -- delete HOST PORT path_server [--recursive [--yes]] [--dry-run]");
+    eprintln!("          (a directory that is not empty needs --recursive, and --yes to confirm. --dry-run lists what would be removed)");
+    eprintln!("  Client: cargo run -- mkdir HOST PORT path_server");
+    eprintln!("  Client: cargo run -- ls HOST PORT path_server [--recursive] [--depth N] [--crc] [--long|--json]");
+    // cargo run ls 127.0.0.1 52709 "./large" --long
+    // cargo run ls 127.0.0.1 52709 "." --recursive --depth 2 --json
+    eprintln!("  Client: cargo run -- stat HOST PORT path_server [--hash] [--json]");
+    // cargo run stat 127.0.0.1 52709 "./large/Bremshley Treadmill Service Manual.pdf" --hash
+    eprintln!("  Client: cargo run -- move HOST PORT from_path_server to_path_server [--overwrite|--skip-existing]");
+    // cargo run move 127.0.0.1 52709 "./large/Bremshley Treadmill Service Manual.pdf" "./manuals/Bremshley Treadmill Service Manual.pdf"
+    eprintln!("  Client: cargo run -- copy HOST PORT from_path_server to_path_server [--overwrite|--skip-existing]");
+    // cargo run copy 127.0.0.1 52709 "./large" "./large_backup" --skip-existing
     // cargo run delete 127.0.0.1 52709 "./large/Bremshley Treadmill Service Manual.pdf"
     // cargo run delete 127.0.0.1 52709 "./untitled folder"
+    eprintln!("  Client: cargo run -- sync HOST PORT src_dir_local dest_dir_server [--delete] [--hash] [--dry-run]");
+    eprintln!("  Client: cargo run -- sync HOST PORT dir_local dir_server --two-way [--dry-run]");
+    // cargo run sync 127.0.0.1 52709 "/home/ray/MEGA/Rays/Programming/LLM" "./Sync/Programming/LLM" --dry-run
+    // cargo run sync XXPA201LAP00072.local 52710 "/home/ray/MEGA/Rays/network" "./Sync/network" --delete
+    // cargo run sync XXPA201LAP00072.local 52710 "/home/ray/MEGA/Rays/network" "./Sync/network" --two-way
+    eprintln!("  Client: cargo run -- follow HOST PORT src_dir_server dest_dir_local [--delete] [--settle SECS]");
+    // cargo run follow XXPA201LAP00072.local 52710 "./Sync/network" "/home/ray/MEGA/Rays" --delete
     eprintln!("\nExample:");
     eprintln!("  1. Terminal 1: cargo run -- server");
     eprintln!("  2. Terminal 2: cargo run -- client \"Hello, World!\"");
 }
 
+fn get_arg_value(args:&[String], name:&str) -> Option<String> {
+    //value following a named flag, e.g. --depth 2
+    args.iter().position(|arg| arg == name).and_then(|iarg| args.get(iarg+1).cloned())
+}
+
+fn json_escape(value:&str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn print_list(entries:&[ListEntry], long:bool, json:bool) {
+    if json {
+        let items: Vec<String> = entries.iter().map(|entry| {
+            let entry_type = EntryType::from_u8(entry.entry_type).unwrap_or(EntryType::Other);
+            let crc = match entry.crc {
+                Some(crc) => format!("\"{:016x}\"", crc),
+                None => String::from("null"),
+            };
+            format!("{{\"path\":\"{}\",\"type\":\"{}\",\"size\":{},\"mtime\":{},\"crc\":{}}}", json_escape(&entry.path), entry_type.as_str(), entry.size, entry.mtime, crc)
+        }).collect();
+        println!("[{}]", items.join(","));
+    } else {
+        for entry in entries {
+            let entry_type = EntryType::from_u8(entry.entry_type).unwrap_or(EntryType::Other);
+            let name = if entry_type == EntryType::Dir {format!("{}/", entry.path)} else {entry.path.clone()};
+            if long {
+                let type_char = match entry_type {
+                    EntryType::File => '-',
+                    EntryType::Dir => 'd',
+                    EntryType::Symlink => 'l',
+                    EntryType::Other => '?',
+                };
+                let crc = entry.crc.map(|crc| format!("{:016x} ", crc)).unwrap_or_default();
+                println!("{} {:>14} {:>12} {}{}", type_char, entry.size, entry.mtime, crc, name);
+            } else {
+                println!("{}", name);
+            }
+        }
+    }
+}
+
+fn print_stat(path:&str, stat:&StatServerResponse, json:bool) {
+    let entry_type = EntryType::from_u8(stat.entry_type).unwrap_or(EntryType::Other);
+    if json {
+        let symlink_target = match &stat.symlink_target {
+            Some(target) => format!("\"{}\"", json_escape(target)),
+            None => String::from("null"),
+        };
+        let crc = match stat.crc {
+            Some(crc) => format!("\"{:016x}\"", crc),
+            None => String::from("null"),
+        };
+        println!("{{\"path\":\"{}\",\"exists\":{},\"type\":\"{}\",\"size\":{},\"mtime\":{},\"permissions\":\"{:o}\",\"readonly\":{},\"symlink_target\":{},\"crc\":{}}}",
+            json_escape(path), stat.exists, entry_type.as_str(), stat.size, stat.mtime, stat.permissions, stat.readonly, symlink_target, crc);
+    } else if !stat.exists {
+        println!("{}: does not exist", path);
+    } else {
+        println!("path: {}", path);
+        println!("type: {}", entry_type.as_str());
+        println!("size: {} ({})", stat.size, format_bytes(stat.size));
+        println!("mtime: {}", stat.mtime);
+        println!("permissions: {:o}{}", stat.permissions, if stat.readonly {" (readonly)"} else {""});
+        if let Some(target) = &stat.symlink_target {
+            println!("symlink target: {}", target);
+        }
+        if let Some(crc) = stat.crc {
+            println!("crc: {:016x}", crc);
+        }
+    }
+}
+
 fn main() {
     setup_logger(LevelFilter::Trace);
 
@@ -351,7 +1705,50 @@ fn main() {
     if args.contains(&"--overwrite".to_string()) {
         is_continue = false;
     }
-    if args[1]==String::from("server") {
+    let mut transfer_options = TransferOptions::default();
+    if let Some(chunk_size) = get_arg_value(&args, "--chunk-size") {
+        //a fixed chunk size, no resizing
+        transfer_options.chunk_size = chunk_size.parse().expect("error parsing --chunk-size to usize");
+        transfer_options.chunk_target = Duration::ZERO;
+    }
+    if let Some(min_chunk_size) = get_arg_value(&args, "--min-chunk-size") {
+        transfer_options.min_chunk_size = min_chunk_size.parse().expect("error parsing --min-chunk-size to usize");
+    }
+    if let Some(max_chunk_size) = get_arg_value(&args, "--max-chunk-size") {
+        transfer_options.max_chunk_size = max_chunk_size.parse().expect("error parsing --max-chunk-size to usize");
+    }
+    if let Some(chunk_target) = get_arg_value(&args, "--chunk-target") {
+        transfer_options.chunk_target = Duration::from_secs_f64(chunk_target.parse().expect("error parsing --chunk-target to seconds"));
+    }
+    if let Some(window) = get_arg_value(&args, "--window") {
+        transfer_options.window = window.parse().expect("error parsing --window to usize");
+    }
+    if let Some(compression) = get_arg_value(&args, "--compress") {
+        transfer_options.compression = Compression::from_name(&compression).expect("--compress should be none, zstd or lz4");
+    }
+    if let Some(level) = get_arg_value(&args, "--compress-level") {
+        transfer_options.compression_level = level.parse().expect("error parsing --compress-level to i32");
+    }
+    if args.contains(&"--delta".to_string()) {
+        transfer_options.delta = true;
+    }
+    if let Some(connections) = get_arg_value(&args, "--connections") {
+        transfer_options.connections = connections.parse().expect("error parsing --connections to usize");
+    }
+    if let Some(max_restarts) = get_arg_value(&args, "--max-restarts") {
+        transfer_options.max_restarts = max_restarts.parse().expect("error parsing --max-restarts to u32");
+    }
+    if let Some(retries) = get_arg_value(&args, "--retries") {
+        transfer_options.retries = retries.parse().expect("error parsing --retries to u32");
+    }
+    if let Some(timeout) = get_arg_value(&args, "--connect-timeout") {
+        transfer_options.connect_timeout = Duration::from_secs_f64(timeout.parse().expect("error parsing --connect-timeout to seconds"));
+    }
+    if let Some(timeout) = get_arg_value(&args, "--timeout") {
+        transfer_options.read_timeout = Duration::from_secs_f64(timeout.parse().expect("error parsing --timeout to seconds"));
+        transfer_options.write_timeout = transfer_options.read_timeout;
+    }
+    if args[1]=="server" {
         if args.len() < 4 {
             print_usage();
             process::exit(1);
@@ -360,16 +1757,101 @@ fn main() {
         let port = args[3].clone();
         //the rest come in pairs
         let mut root_path: Option<PathBuf> = None;
+        let mut io_timeout = DEFAULT_IO_TIMEOUT;
+        let mut hash_cache_entries = DEFAULT_HASH_CACHE_ENTRIES;
+        let mut max_connections = DEFAULT_MAX_CONNECTIONS;
         for iarg in (4..args.len()).step_by(2) {
             if args[iarg] == "--path" {
                 root_path = Some(PathBuf::from(&args[iarg+1]));
+            } else if args[iarg] == "--timeout" {
+                io_timeout = Duration::from_secs_f64(args[iarg+1].parse().expect("error parsing --timeout to seconds"));
+            } else if args[iarg] == "--hash-cache" {
+                hash_cache_entries = args[iarg+1].parse().expect("error parsing --hash-cache to usize");
+            } else if args[iarg] == "--max-connections" {
+                max_connections = args[iarg+1].parse().expect("error parsing --max-connections to usize");
             }
         }
-        if let Err(err) = run_server(&host, &port, root_path) {
+        if let Err(err) = run_server(&host, &port, root_path, io_timeout, hash_cache_entries, max_connections) {
             eprint!("Server error: {}", err);
             process::exit(1);
         }
-    } else if args[1]==String::from("upload") {
+    } else if args[1]=="upload" {
+        if args.len() < 6 {
+            print_usage();
+            process::exit(1);
+        }
+        let host = args[2].clone();
+        let port: u16 = args[3].clone().parse().expect("error parsing port to u16");
+        let src = PathBuf::from(&args[4]);
+        let dest = PathBuf::from(&args[5]);
+        if args.contains(&"--watch".to_string()) {
+            let settle = get_arg_value(&args, "--settle").map(|secs| Duration::from_secs_f64(secs.parse().expect("error parsing --settle to seconds"))).unwrap_or(DEFAULT_SETTLE);
+            let delete_remote = args.contains(&"--watch-delete".to_string());
+            watch_and_upload(&host, port, src, dest, settle, delete_remote, &transfer_options).expect("Error in watch_and_upload")
+        } else {
+            let stats = upload_file_to_server(&host, port, src, dest, is_continue, &transfer_options).expect("Error in upload_file_to_server");
+            info!("Uploaded {} ({} retries, {} restarts)", format_bytes(stats.bytes_transferred), stats.retries, stats.restarts);
+        }
+    } else if args[1]=="download" {
+        if args.len() < 6 {
+            print_usage();
+            process::exit(1);
+        }
+        let host = args[2].clone();
+        let port: u16 = args[3].clone().parse().expect("error parsing port to u16");
+        let src = PathBuf::from(&args[4]);
+        let dest = PathBuf::from(&args[5]);
+        let stats = download_file_from_server(&host, port, src, dest, is_continue, &transfer_options).expect("Error in download_file_from_server");
+        info!("Downloaded {} ({} retries, {} restarts)", format_bytes(stats.bytes_transferred), stats.retries, stats.restarts);
+    } else if args[1]=="delete" {
+        if args.len() < 5 {
+            print_usage();
+            process::exit(1);
+        }
+        let host = args[2].clone();
+        let port: u16 = args[3].clone().parse().expect("error parsing port to u16");
+        let path = PathBuf::from(&args[4]);
+        let recursive = args.contains(&"--recursive".to_string());
+        let confirm = args.contains(&"--yes".to_string());
+        let dry_run = args.contains(&"--dry-run".to_string());
+        let removed = delete_path_from_server(&host, port, path, recursive, confirm, dry_run, &transfer_options).expect("Error in delete_file_from_server");
+        for removed_path in removed {
+            if dry_run {
+                println!("would remove: {}", removed_path);
+            } else {
+                info!("removed: {}", removed_path);
+            }
+        }
+    } else if args[1]=="mkdir" {
+        if args.len() < 5 {
+            print_usage();
+            process::exit(1);
+        }
+        let host = args[2].clone();
+        let port: u16 = args[3].clone().parse().expect("error parsing port to u16");
+        let path = PathBuf::from(&args[4]);
+        make_dir_on_server(&host, port, path, &transfer_options).expect("Error in make_dir_on_server")
+    } else if args[1]=="move" || args[1]=="copy" {
+        if args.len() < 6 {
+            print_usage();
+            process::exit(1);
+        }
+        let host = args[2].clone();
+        let port: u16 = args[3].clone().parse().expect("error parsing port to u16");
+        let from = PathBuf::from(&args[4]);
+        let to = PathBuf::from(&args[5]);
+        let mut overwrite_policy = OverwritePolicy::Fail;
+        if args.contains(&"--overwrite".to_string()) {
+            overwrite_policy = OverwritePolicy::Overwrite;
+        } else if args.contains(&"--skip-existing".to_string()) {
+            overwrite_policy = OverwritePolicy::Skip;
+        }
+        if args[1]=="move" {
+            move_path_on_server(&host, port, from, to, overwrite_policy, &transfer_options).expect("Error in move_path_on_server")
+        } else {
+            copy_path_on_server(&host, port, from, to, overwrite_policy, &transfer_options).expect("Error in copy_path_on_server")
+        }
+    } else if args[1]=="sync" {
         if args.len() < 6 {
             print_usage();
             process::exit(1);
@@ -378,8 +1860,28 @@ fn main() {
         let port: u16 = args[3].clone().parse().expect("error parsing port to u16");
         let src = PathBuf::from(&args[4]);
         let dest = PathBuf::from(&args[5]);
-        upload_file_to_server(&host, port, src, dest, is_continue, None).expect("Error in upload_file_to_server")
-    } else if args[1]==String::from("download") {
+        let sync_options = SyncOptions {
+            delete_extras: args.contains(&"--delete".to_string()),
+            use_hash: args.contains(&"--hash".to_string()),
+            dry_run: args.contains(&"--dry-run".to_string()),
+        };
+        let dry_run = sync_options.dry_run;
+        let actions = if args.contains(&"--two-way".to_string()) {
+            sync_dir_two_way(&host, port, src, dest, dry_run, &transfer_options).expect("Error in sync_dir_two_way")
+        } else {
+            sync_dir_to_server(&host, port, src, dest, sync_options, &transfer_options).expect("Error in sync_dir_to_server")
+        };
+        for action in &actions {
+            if dry_run {
+                println!("{} {} ({})", action.action.as_str(), action.path, format_bytes(action.size));
+            } else if action.action == SyncActionType::Conflict {
+                println!("conflict: {}", action.path);
+            }
+        }
+        if actions.is_empty() {
+            info!("Already in sync");
+        }
+    } else if args[1]=="follow" {
         if args.len() < 6 {
             print_usage();
             process::exit(1);
@@ -388,8 +1890,10 @@ fn main() {
         let port: u16 = args[3].clone().parse().expect("error parsing port to u16");
         let src = PathBuf::from(&args[4]);
         let dest = PathBuf::from(&args[5]);
-        download_file_from_server(&host, port, src, dest, is_continue, None).expect("Error in download_file_from_server")
-    } else if args[1]==String::from("delete") {
+        let settle = get_arg_value(&args, "--settle").map(|secs| Duration::from_secs_f64(secs.parse().expect("error parsing --settle to seconds"))).unwrap_or(DEFAULT_SETTLE);
+        let delete_local = args.contains(&"--delete".to_string());
+        follow_server_dir(&host, port, src, dest, settle, delete_local, &transfer_options).expect("Error in follow_server_dir")
+    } else if args[1]=="ls" {
         if args.len() < 5 {
             print_usage();
             process::exit(1);
@@ -397,9 +1901,56 @@ fn main() {
         let host = args[2].clone();
         let port: u16 = args[3].clone().parse().expect("error parsing port to u16");
         let path = PathBuf::from(&args[4]);
-        delete_path_from_server(&host, port, path).expect("Error in delete_file_from_server")
+        let recursive = args.contains(&"--recursive".to_string());
+        let max_depth: Option<u32> = get_arg_value(&args, "--depth").map(|depth| depth.parse().expect("error parsing --depth to u32"));
+        let include_crc = args.contains(&"--crc".to_string());
+        let long = args.contains(&"--long".to_string());
+        let json = args.contains(&"--json".to_string());
+        let entries = list_path_on_server(&host, port, path, recursive || max_depth.is_some(), max_depth, include_crc, &transfer_options).expect("Error in list_path_on_server");
+        print_list(&entries, long, json);
+    } else if args[1]=="stat" {
+        if args.len() < 5 {
+            print_usage();
+            process::exit(1);
+        }
+        let host = args[2].clone();
+        let port: u16 = args[3].clone().parse().expect("error parsing port to u16");
+        let path = PathBuf::from(&args[4]);
+        let include_hash = args.contains(&"--hash".to_string());
+        let json = args.contains(&"--json".to_string());
+        let stat = stat_path_on_server(&host, port, path, include_hash, &transfer_options).expect("Error in stat_path_on_server");
+        print_stat(&args[4], &stat, json);
+        if !stat.exists {
+            process::exit(1);
+        }
     } else {
         print_usage();
         process::exit(1);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sendfile_after_crc_cached() {
+        //the first download of a file hashes it on the way out, once End has cached the crc the next one is sent with sendfile
+        let hash_cache = HashCache::new(DEFAULT_HASH_CACHE_ENTRIES);
+        let version: FileVersion = (PathBuf::from("/srv/a.bin"), 10, 1);
+        assert!(!is_sendfile(&hash_cache, &version, Compression::None));
+        hash_cache.insert(version.clone(), 42);
+        assert!(is_sendfile(&hash_cache, &version, Compression::None));
+        assert!(!is_sendfile(&hash_cache, &version, Compression::Zstd));
+        //another size or mtime is another version of the file
+        assert!(!is_sendfile(&hash_cache, &(PathBuf::from("/srv/a.bin"), 10, 2), Compression::None));
+    }
+
+    #[test]
+    fn test_no_sendfile_without_cache() {
+        let hash_cache = HashCache::new(0);
+        let version: FileVersion = (PathBuf::from("/srv/a.bin"), 10, 1);
+        hash_cache.insert(version.clone(), 42);
+        assert!(!is_sendfile(&hash_cache, &version, Compression::None));
+    }
+}
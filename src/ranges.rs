@@ -0,0 +1,465 @@
+use crc_fast::{CrcAlgorithm::Crc64Nvme, Digest};
+use log::*;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use helper_lib::paths::format_bytes;
+use crate::{ChunkSizer, DownloadClientTransfer, DownloadServerInitalise, FileCopyStep, SIGNATURE, TransferOptions, TransferStats, UploadClientTransfer, UploadRange, UploadServerTransfer, digest_file_range, download_chunk, file_len_mtime, request_error, send_chunk, with_retries};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeProgress {
+    pub start: u64,
+    pub end: u64,
+    pub done: u64, //bytes written from start
+}
+
+#[derive(Clone, Debug)]
+pub struct RangesState {
+    pub filelen: u64,
+    pub mtime: u64, //mtime and crc of the file being downloaded, 0 for uploads and for a crc not known when the download started
+    pub crc: u64,
+    pub ranges: Vec<RangeProgress>,
+}
+impl RangesState {
+    pub fn new(filelen: u64, mtime: u64, crc: u64, count: usize) -> RangesState {
+        //count ranges of about the same length, the last one takes the remainder
+        let count = (count.max(1) as u64).min(filelen.max(1));
+        let range_len = filelen / count;
+        let ranges = (0..count).map(|irange| RangeProgress {
+            start: irange * range_len,
+            end: if irange == count - 1 {filelen} else {(irange + 1) * range_len},
+            done: 0,
+        }).collect();
+        RangesState {
+            filelen: filelen,
+            mtime: mtime,
+            crc: crc,
+            ranges: ranges,
+        }
+    }
+
+    pub fn record(&mut self, offset: u64, len: u64) {
+        //bytes written at offset complete their range up to offset+len, as long as they follow on from what was done before
+        for range in self.ranges.iter_mut() {
+            if offset >= range.start && offset < range.end && offset <= range.start + range.done {
+                range.done = (offset + len).min(range.end) - range.start;
+                return;
+            }
+        }
+    }
+
+    pub fn after_prefix(filelen: u64, mtime: u64, crc: u64, count: usize, prefix_len: u64) -> RangesState {
+        //the first prefix_len bytes are already done as a range of their own, the rest is split as new does
+        let mut state = RangesState::new(filelen - prefix_len, mtime, crc, count);
+        state.filelen = filelen;
+        for range in state.ranges.iter_mut() {
+            range.start += prefix_len;
+            range.end += prefix_len;
+        }
+        if prefix_len > 0 {
+            state.ranges.insert(0, RangeProgress {
+                start: 0,
+                end: prefix_len,
+                done: prefix_len,
+            });
+        }
+        state
+    }
+
+    pub fn done(&self) -> u64 {
+        self.ranges.iter().map(|range| range.done).sum()
+    }
+}
+
+pub fn read_ranges(ranges_file:&Path) -> Option<RangesState> {
+	//text file: a header line "tfcranges1<TAB>filelen<TAB>mtime<TAB>crc", then "start<TAB>end<TAB>done" per range. None when missing or unreadable
+	let text = fs::read_to_string(ranges_file).ok()?;
+	let mut lines = text.lines();
+	let header: Vec<&str> = lines.next()?.split('\t').collect();
+	if header.len() != 4 || header[0] != "tfcranges1" {
+		return None;
+	}
+	let mut state = RangesState {
+		filelen: header[1].parse().ok()?,
+		mtime: header[2].parse().ok()?,
+		crc: header[3].parse().ok()?,
+		ranges: Vec::new(),
+	};
+	for line in lines {
+		let fields: Vec<&str> = line.split('\t').collect();
+		if fields.len() != 3 {
+			return None;
+		}
+		state.ranges.push(RangeProgress {
+			start: fields[0].parse().ok()?,
+			end: fields[1].parse().ok()?,
+			done: fields[2].parse().ok()?,
+		});
+	}
+	Some(state)
+}
+
+pub fn write_ranges(ranges_file:&Path, state:&RangesState) -> Result<(), std::io::Error> {
+	//.name.tmp.tfcranges, still hidden like the ranges file itself
+	let tmp_path = ranges_file.with_extension("tmp.tfcranges");
+	{
+		let mut file = File::create(&tmp_path)?;
+		writeln!(file, "tfcranges1\t{}\t{}\t{}", state.filelen, state.mtime, state.crc)?;
+		for range in &state.ranges {
+			writeln!(file, "{}\t{}\t{}", range.start, range.end, range.done)?;
+		}
+	}
+	fs::rename(&tmp_path, ranges_file)
+}
+
+pub(crate) fn partial_ranges(part_path:&Path, ranges_file:&Path) -> Vec<UploadRange> {
+	//the ranges recorded for a ranged partial file, with the crc of what each holds for the server to confirm. a range that cannot be read is sent as not started
+	let Some(state) = read_ranges(ranges_file) else {
+		return Vec::new();
+	};
+	state.ranges.iter().map(|range| {
+		let crc = if range.done > 0 {digest_file_range(part_path, range.start, range.done).ok().map(|digest| digest.finalize())} else {None};
+		UploadRange {
+			start: range.start,
+			end: range.end,
+			done: if crc.is_some() {range.done} else {0},
+			crc: crc.unwrap_or(0),
+		}
+	}).collect()
+}
+
+type RangeWorker = JoinHandle<(Result<bool, std::io::Error>, TransferStats)>;
+
+fn join_workers(address:&str, workers:Vec<RangeWorker>, stats:&mut TransferStats) -> Result<bool, Box<dyn Error>> {
+	//false when a worker saw the source file change. the first error wins over that
+	let mut is_unchanged = true;
+	let mut first_error: Option<std::io::Error> = None;
+	for worker in workers {
+		let (result, worker_stats) = worker.join().expect("range thread panicked");
+		stats.add(&worker_stats);
+		match result {
+			Ok(unchanged) => is_unchanged &= unchanged,
+			Err(e) => {
+				if first_error.is_none() {
+					first_error = Some(e);
+				}
+			}
+		}
+	}
+	if let Some(e) = first_error {
+		return Err(request_error(address, e));
+	}
+	Ok(is_unchanged)
+}
+
+pub fn download_ranges(address:&str, src:&Path, dest:&Path, download_server_initalise:&DownloadServerInitalise, options:&TransferOptions, stats:&mut TransferStats) -> Result<bool, Box<dyn Error>> {
+/*
+Ranged Download:
+1. preallocate the partial file for dest and split it into byte ranges, or pick up the ranges recorded by an interrupted ranged download of the same file.
+   ranges the server did not confirm at Initialise start again, and a sequential partial file it confirmed is kept as the first range
+2. one thread per unfinished range requests its chunks in order and writes them at their offsets, recording progress in the ranges file
+Returns false if the file changed on the server, leaving the caller to start again.
+*/
+
+	let part_path = crate::partial_path(dest);
+	let ranges_file = crate::ranges_path(dest);
+	let filelen = download_server_initalise.filelen;
+	//the crc may not be known until the end, so the size and mtime say whether it is the same file, and the server has checked each range
+	let resumed = read_ranges(&ranges_file).filter(|state| {
+		state.filelen == filelen
+			&& state.mtime == download_server_initalise.mtime
+			&& (state.crc == 0 || download_server_initalise.crc.is_none_or(|crc| crc == state.crc))
+			&& state.ranges.len() == download_server_initalise.ranges_match.len()
+			&& part_path.metadata().map(|metadata| metadata.len() == filelen).unwrap_or(false)
+	});
+	let state = match resumed {
+		Some(mut state) => {
+			for (range, range_matches) in state.ranges.iter_mut().zip(&download_server_initalise.ranges_match) {
+				if range.done > 0 && !range_matches {
+					warn!("Range {}-{} of {} does not match the file on the server, downloading it again", range.start, range.end, part_path.to_string_lossy());
+					range.done = 0;
+				}
+			}
+			write_ranges(&ranges_file, &state)?;
+			info!("Resuming ranged download of {}, {} of {} done", src.to_string_lossy(), format_bytes(state.done()), format_bytes(filelen));
+			state
+		}
+		None => {
+			//a sequential partial file is only still here if the server confirmed it is the start of the file
+			let prefix_len = if download_server_initalise.prefix_matches {part_path.metadata().map(|metadata| metadata.len()).unwrap_or(0).min(filelen)} else {0};
+			let file = if prefix_len > 0 {
+				info!("Continuing {} over several connections after the {} already downloaded", src.to_string_lossy(), format_bytes(prefix_len));
+				OpenOptions::new().write(true).open(&part_path)?
+			} else {
+				File::create(&part_path)?
+			};
+			file.set_len(filelen)?;
+			let state = RangesState::after_prefix(filelen, download_server_initalise.mtime, download_server_initalise.crc.unwrap_or(0), options.connections, prefix_len);
+			write_ranges(&ranges_file, &state)?;
+			state
+		}
+	};
+
+	let done = Arc::new(AtomicU64::new(state.done()));
+	let stop = Arc::new(AtomicBool::new(false));
+	let pending_ranges: Vec<RangeProgress> = state.ranges.iter().filter(|range| range.start + range.done < range.end).cloned().collect();
+	let state = Arc::new(Mutex::new(state));
+	let mut workers: Vec<RangeWorker> = Vec::new();
+	for mut range in pending_ranges {
+		let address = address.to_string();
+		let serverside_path = src.to_string_lossy().to_string();
+		let part_path = part_path.clone();
+		let ranges_file = ranges_file.clone();
+		let options = options.clone();
+		let mtime = download_server_initalise.mtime;
+		let (state, done, stop) = (Arc::clone(&state), Arc::clone(&done), Arc::clone(&stop));
+		workers.push(thread::spawn(move || {
+			let mut worker_stats = TransferStats::default();
+			let mut chunk_sizer = ChunkSizer::new(&options);
+			let mut run = || -> Result<bool, std::io::Error> {
+				let mut file = OpenOptions::new().write(true).open(&part_path)?;
+				while range.start + range.done < range.end {
+					if stop.load(Ordering::Relaxed) {
+						return Ok(true);
+					}
+					let from_byte = range.start + range.done;
+					let download_client_transfer = DownloadClientTransfer {
+						serverside_path: serverside_path.clone(),
+						from_byte: from_byte,
+						chunk_size: (range.end - from_byte).min(chunk_sizer.size()) as usize,
+						compression: options.compression.to_u8(),
+						compression_level: options.compression_level,
+					};
+					let serialized = wincode::serialize(&download_client_transfer).map_err(|e| std::io::Error::other(e.to_string()))?;
+					let step:FileCopyStep = FileCopyStep::Transfer;
+					let package = [SIGNATURE.to_vec(), vec![0u8], vec![step.to_u8()], serialized].concat();
+					let requested_at = Instant::now();
+					let (download_server_transfer, nbytes) = download_chunk(&address, &package, (filelen, mtime), &mut file, from_byte, &options, &mut worker_stats)?;
+					if download_server_transfer.filelen != filelen || download_server_transfer.mtime != mtime {
+						return Ok(false);
+					}
+					if let Some(errmsg) = download_server_transfer.error_msg {
+						return Err(std::io::Error::other(errmsg));
+					}
+					chunk_sizer.record(nbytes, requested_at.elapsed());
+					range.done += nbytes;
+					worker_stats.bytes_transferred += nbytes;
+					{
+						let mut state = state.lock().expect("ranges state lock poisoned");
+						state.record(from_byte, nbytes);
+						write_ranges(&ranges_file, &state)?;
+					}
+					let done = done.fetch_add(nbytes, Ordering::Relaxed) + nbytes;
+					info!("{:.1}% {}/{}", done as f64 / filelen as f64 * 100.0, format_bytes(done), format_bytes(filelen));
+				}
+				Ok(true)
+			};
+			let result = run();
+			if !matches!(result, Ok(true)) {
+				//the other ranges stop too, this download is either failing or starting again
+				stop.store(true, Ordering::Relaxed);
+			}
+			(result, worker_stats)
+		}));
+	}
+
+	if !join_workers(address, workers, stats)? {
+		return Ok(false);
+	}
+	fs::remove_file(&ranges_file)?;
+	Ok(true)
+}
+
+pub fn upload_ranges(address:&str, src:&Path, dest:&Path, src_len_mtime:(u64, u64), ranges:&[UploadRange], options:&TransferOptions, stats:&mut TransferStats) -> Result<Option<u64>, Box<dyn Error>> {
+/*
+Ranged Upload:
+1. the server has preallocated the partial file and reported how far each range got, with a crc of those bytes
+2. ranges whose bytes do not match this file are sent again from their start
+3. one thread per unfinished range sends its chunks at their offsets, the server records the progress
+Returns the crc of src from the bytes checked and sent, or None if src changed while being sent, leaving the caller to start again.
+*/
+
+	//the size and mtime src had when the upload started, the server preallocated for that size
+	let (filelen, mtime) = src_len_mtime;
+	let mut pending_ranges: Vec<(usize, RangeProgress)> = Vec::new();
+	//crc of each range so far, combined in order once they are all sent
+	let mut range_digests: Vec<Digest> = Vec::new();
+	for (irange, range) in ranges.iter().enumerate() {
+		let mut done = range.done;
+		let mut digest = Digest::new(Crc64Nvme);
+		if done > 0 {
+			digest = digest_file_range(src, range.start, done)?;
+			if digest.finalize() != range.crc {
+				warn!("Range {}-{} on server does not match {}, sending it again", range.start, range.end, src.to_string_lossy());
+				done = 0;
+				digest = Digest::new(Crc64Nvme);
+			}
+		}
+		range_digests.push(digest);
+		if range.start + done < range.end {
+			pending_ranges.push((irange, RangeProgress {
+				start: range.start,
+				end: range.end,
+				done: done,
+			}));
+		}
+	}
+
+	let done = Arc::new(AtomicU64::new(filelen - pending_ranges.iter().map(|(_, range)| range.end - range.start - range.done).sum::<u64>()));
+	let stop = Arc::new(AtomicBool::new(false));
+	let range_digests = Arc::new(Mutex::new(range_digests));
+	let mut workers: Vec<RangeWorker> = Vec::new();
+	for (irange, mut range) in pending_ranges {
+		let address = address.to_string();
+		let serverside_path = dest.to_string_lossy().to_string();
+		let src: PathBuf = src.to_path_buf();
+		let options = options.clone();
+		let (done, stop, range_digests) = (Arc::clone(&done), Arc::clone(&stop), Arc::clone(&range_digests));
+		workers.push(thread::spawn(move || {
+			let mut worker_stats = TransferStats::default();
+			let mut chunk_sizer = ChunkSizer::new(&options);
+			let mut run = || -> Result<bool, std::io::Error> {
+				let mut file = File::open(&src)?;
+				while range.start + range.done < range.end {
+					if stop.load(Ordering::Relaxed) {
+						return Ok(true);
+					}
+					if file_len_mtime(&src)? != (filelen, mtime) {
+						return Ok(false);
+					}
+					let offset = range.start + range.done;
+					let nbytes = (range.end - offset).min(chunk_sizer.size());
+					let upload_client_transfer = UploadClientTransfer {
+						serverside_path: serverside_path.clone(),
+						offset: offset,
+						compression: options.compression.to_u8(),
+					};
+					let serialized = wincode::serialize(&upload_client_transfer).map_err(|e| std::io::Error::other(e.to_string()))?;
+					let header_len: u64 = serialized.len() as u64;
+					let step:FileCopyStep = FileCopyStep::Transfer;
+					let head = [SIGNATURE.to_vec(), vec![1u8], vec![step.to_u8()], header_len.to_le_bytes().to_vec(), serialized].concat();
+					let sent_at = Instant::now();
+					let (buffer_from_server, nbytes_sent, chunk_digest) = with_retries(&address, &options, &mut worker_stats, || send_chunk(&address, &head, &mut file, offset, nbytes, &options))?;
+					if nbytes_sent < nbytes {
+						return Ok(false);
+					}
+					let upload_server_transfer: UploadServerTransfer = wincode::deserialize(&buffer_from_server).expect("Could not deserialize bytes to UploadServerTransfer");
+					if let Some(errmsg) = upload_server_transfer.error_msg {
+						return Err(std::io::Error::other(errmsg));
+					}
+					if upload_server_transfer.filelen != filelen {
+						return Err(std::io::Error::other(format!("File on server is {} bytes, expected the preallocated {}", upload_server_transfer.filelen, filelen)));
+					}
+					chunk_sizer.record(nbytes, sent_at.elapsed());
+					range_digests.lock().expect("range digests lock poisoned")[irange].combine(&chunk_digest);
+					range.done += nbytes;
+					worker_stats.bytes_transferred += nbytes;
+					let done = done.fetch_add(nbytes, Ordering::Relaxed) + nbytes;
+					info!("{:.1}% {}/{}", done as f64 / filelen as f64 * 100.0, format_bytes(done), format_bytes(filelen));
+				}
+				Ok(true)
+			};
+			let result = run();
+			if !matches!(result, Ok(true)) {
+				stop.store(true, Ordering::Relaxed);
+			}
+			(result, worker_stats)
+		}));
+	}
+
+	if !join_workers(address, workers, stats)? {
+		return Ok(None);
+	}
+	let mut digest = Digest::new(Crc64Nvme);
+	for range_digest in range_digests.lock().expect("range digests lock poisoned").iter() {
+		digest.combine(range_digest);
+	}
+	Ok(Some(digest.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_path;
+
+    fn temp_ranges_file() -> PathBuf {
+        temp_path(".tfc_ranges_test_", crate::RANGES_SUFFIX)
+    }
+
+    #[test]
+    fn test_new_splits_whole_file() {
+        let state = RangesState::new(1_003, 7, 9, 4);
+        assert_eq!(state.ranges.len(), 4);
+        assert_eq!(state.ranges[0].start, 0);
+        assert_eq!(state.ranges[3].end, 1_003);
+        for pair in state.ranges.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+        assert_eq!(state.done(), 0);
+    }
+
+    #[test]
+    fn test_new_more_ranges_than_bytes() {
+        assert_eq!(RangesState::new(3, 0, 0, 8).ranges.len(), 3);
+        let state = RangesState::new(0, 0, 0, 8);
+        assert_eq!(state.ranges, vec![RangeProgress {start: 0, end: 0, done: 0}]);
+    }
+
+    #[test]
+    fn test_record() {
+        let mut state = RangesState::new(1_000, 0, 0, 2);
+        state.record(0, 100);
+        state.record(100, 100);
+        assert_eq!(state.ranges[0].done, 200);
+        //bytes that do not follow on from what was done leave the range as it is
+        state.record(600, 50);
+        assert_eq!(state.ranges[1].done, 0);
+        //and a range is never done past its end
+        state.record(500, 900);
+        assert_eq!(state.ranges[1].done, 500);
+        assert_eq!(state.done(), 700);
+    }
+
+    #[test]
+    fn test_after_prefix() {
+        let state = RangesState::after_prefix(1_000, 0, 0, 3, 400);
+        assert_eq!(state.filelen, 1_000);
+        assert_eq!(state.ranges.len(), 4);
+        assert_eq!(state.ranges[0], RangeProgress {start: 0, end: 400, done: 400});
+        assert_eq!(state.ranges[1].start, 400);
+        assert_eq!(state.ranges[3].end, 1_000);
+        assert_eq!(state.done(), 400);
+        //without a prefix it is the same as new
+        assert_eq!(RangesState::after_prefix(1_000, 0, 0, 3, 0).ranges, RangesState::new(1_000, 0, 0, 3).ranges);
+    }
+
+    #[test]
+    fn test_write_read_round_trip() {
+        let ranges_file = temp_ranges_file();
+        let mut state = RangesState::new(123_456_789, 1_700_000_000, u64::MAX, 3);
+        state.record(0, 1_000);
+        state.record(state.ranges[2].start, 5);
+        write_ranges(&ranges_file, &state).unwrap();
+        let read_back = read_ranges(&ranges_file);
+        fs::remove_file(&ranges_file).unwrap();
+        let read_back = read_back.expect("ranges file not read back");
+        assert_eq!((read_back.filelen, read_back.mtime, read_back.crc), (state.filelen, state.mtime, state.crc));
+        assert_eq!(read_back.ranges, state.ranges);
+    }
+
+    #[test]
+    fn test_read_rejects_bad_file() {
+        let ranges_file = temp_ranges_file();
+        assert!(read_ranges(&ranges_file).is_none());
+        for text in ["tfcranges0\t1\t2\t3\n", "tfcranges1\t1\t2\n", "tfcranges1\t10\t2\t3\n0\t10\n", "tfcranges1\t10\t2\t3\n0\tten\t0\n"] {
+            fs::write(&ranges_file, text).unwrap();
+            assert!(read_ranges(&ranges_file).is_none(), "{:?} was read", text);
+        }
+        fs::remove_file(&ranges_file).unwrap();
+    }
+}
@@ -0,0 +1,527 @@
+use crc_fast::{checksum_file, CrcAlgorithm::Crc64Nvme};
+use helper_lib::datetime::systemtime_to_unixtimestamp;
+use log::*;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime};
+use crate::{EntryType, ListEntry, TransferOptions, delete_path_from_server, download_file_from_server, is_partial_path, list_path_on_server, make_dir_on_server, stat_path_on_server, upload_file_to_server};
+
+pub const SYNC_STATE_FILENAME: &str = ".tfcsync"; //two-way sync state, kept in the root of the local directory
+pub const SYNC_STATE_TMP_FILENAME: &str = ".tfcsync.tmp"; //written first, then renamed over it
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncActionType {
+    Upload, //new file
+    Update, //changed file, overwritten
+    MakeDir, //empty directory
+    Delete, //remote extra, only with delete_extras
+    Download, //two-way: new or changed on the server
+    DeleteLocal, //two-way: deleted on the server
+    Conflict, //two-way: changed on both sides, local copy kept under a conflict name and the server version taken
+}
+impl SyncActionType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SyncActionType::Upload => "upload",
+            SyncActionType::Update => "update",
+            SyncActionType::MakeDir => "mkdir",
+            SyncActionType::Delete => "delete",
+            SyncActionType::Download => "download",
+            SyncActionType::DeleteLocal => "delete local",
+            SyncActionType::Conflict => "conflict",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SyncOptions {
+    pub delete_extras: bool, //delete server entries that are not local
+    pub use_hash: bool, //files of the same size are compared by crc instead of mtime
+    pub dry_run: bool, //only return the plan
+}
+
+#[derive(Clone, Debug)]
+pub struct SyncAction {
+    pub action: SyncActionType,
+    pub path: String, //relative to the synced directories, '/' separated
+    pub size: u64,
+}
+
+pub fn list_local_dir(dir:&Path, rel:&str, entries:&mut BTreeMap<String, ListEntry>) -> Result<(), std::io::Error> {
+	//same shape as a recursive server listing, keyed by relative path. crcs are left for the caller to fill in when needed
+	for entry in fs::read_dir(dir)? {
+		let entry = entry?;
+		let name = entry.file_name().to_string_lossy().to_string();
+		if name == SYNC_STATE_FILENAME || name == SYNC_STATE_TMP_FILENAME || is_partial_path(&entry.path()) {
+			continue;
+		}
+		let entry_rel = if rel.is_empty() {name} else {format!("{}/{}", rel, name)};
+		let path = entry.path();
+		let metadata = fs::metadata(&path).or_else(|_| entry.metadata())?;
+		let entry_type = if metadata.is_dir() {EntryType::Dir} else if metadata.is_file() {EntryType::File} else {EntryType::Other};
+		entries.insert(entry_rel.clone(), ListEntry {
+			path: entry_rel.clone(),
+			entry_type: entry_type.to_u8(),
+			size: if metadata.is_dir() {0} else {metadata.len()},
+			mtime: systemtime_to_unixtimestamp(metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH)),
+			crc: None,
+		});
+		if entry_type == EntryType::Dir {
+			list_local_dir(&path, &entry_rel, entries)?;
+		}
+	}
+	Ok(())
+}
+
+fn parent_of(dir:&Path, rel:&str) -> PathBuf {
+	//directory that rel sits in, below dir
+	dir.join(rel).parent().expect("no parent for relative path").to_path_buf()
+}
+
+fn plan_sync_to_server(local_entries:&BTreeMap<String, ListEntry>, remote_entries:&BTreeMap<String, ListEntry>, sync_options:SyncOptions) -> Vec<SyncAction> {
+	//with use_hash, the crcs of local files the same size as on the server are already filled in
+	let mut actions: Vec<SyncAction> = Vec::new();
+	for (rel, local) in local_entries {
+		let local_type = EntryType::from_u8(local.entry_type);
+		let remote = remote_entries.get(rel);
+		let remote_type = remote.and_then(|remote| EntryType::from_u8(remote.entry_type));
+		match (local_type, remote) {
+			(Some(EntryType::Dir), None) => {
+				//only empty directories need creating, uploads create the rest
+				let prefix = format!("{}/", rel);
+				if !local_entries.keys().any(|other| other.starts_with(&prefix)) {
+					actions.push(SyncAction { action: SyncActionType::MakeDir, path: rel.clone(), size: 0 });
+				}
+			}
+			(Some(EntryType::File), None) => {
+				actions.push(SyncAction { action: SyncActionType::Upload, path: rel.clone(), size: local.size });
+			}
+			(Some(EntryType::File), Some(remote)) if remote_type == Some(EntryType::File) => {
+				let is_changed = if local.size != remote.size {
+					true
+				} else if sync_options.use_hash {
+					remote.crc != local.crc
+				} else {
+					local.mtime != remote.mtime
+				};
+				if is_changed {
+					actions.push(SyncAction { action: SyncActionType::Update, path: rel.clone(), size: local.size });
+				}
+			}
+			(Some(EntryType::Dir), Some(_)) if remote_type == Some(EntryType::Dir) => {}
+			(Some(EntryType::File), Some(_)) | (Some(EntryType::Dir), Some(_)) => {
+				warn!("Skipping {}, it is a different type on the server", rel);
+			}
+			_ => {
+				debug!("Skipping {}, not a file or directory", rel);
+			}
+		}
+	}
+	if sync_options.delete_extras {
+		let mut deleted_dirs: Vec<String> = Vec::new();
+		for (rel, remote) in remote_entries {
+			if local_entries.contains_key(rel) {
+				continue;
+			}
+			//removing a directory takes its contents with it
+			if deleted_dirs.iter().any(|dir| rel.starts_with(&format!("{}/", dir))) {
+				continue;
+			}
+			if EntryType::from_u8(remote.entry_type) == Some(EntryType::Dir) {
+				deleted_dirs.push(rel.clone());
+			}
+			actions.push(SyncAction { action: SyncActionType::Delete, path: rel.clone(), size: remote.size });
+		}
+	}
+	actions
+}
+
+pub fn sync_dir_to_server(host:&str, port:u16, src:PathBuf, dest:PathBuf, sync_options:SyncOptions, options:&TransferOptions) -> Result<Vec<SyncAction>, Box<dyn Error>> {
+/*
+One-way Sync (mirror):
+1. list the local tree under src and the server tree under dest
+2. plan: upload files missing on the server, re-upload files whose size/mtime (or crc) differ,
+   create empty directories, and optionally delete server entries that are not local
+3. unless sync_options.dry_run, carry out the plan with the normal upload (resume + crc check) and delete operations
+The contents of src are mirrored into dest.
+*/
+
+	if !src.is_dir() {
+		Err(format!("Source path is not a directory on client: {}", src.to_string_lossy()))?
+	}
+
+	let mut local_entries: BTreeMap<String, ListEntry> = BTreeMap::new();
+	list_local_dir(&src, "", &mut local_entries)?;
+
+	let mut remote_entries: BTreeMap<String, ListEntry> = BTreeMap::new();
+	let dest_stat = stat_path_on_server(host, port, dest.clone(), false, options)?;
+	if dest_stat.exists {
+		if EntryType::from_u8(dest_stat.entry_type) != Some(EntryType::Dir) {
+			Err(format!("Destination path is not a directory on server: {}", dest.to_string_lossy()))?
+		}
+		for entry in list_path_on_server(host, port, dest.clone(), true, None, sync_options.use_hash, options)? {
+			remote_entries.insert(entry.path.clone(), entry);
+		}
+	}
+
+	if sync_options.use_hash {
+		//only files the same size on both sides are compared by crc
+		for (rel, local) in local_entries.iter_mut() {
+			if EntryType::from_u8(local.entry_type) == Some(EntryType::File) && remote_entries.get(rel).is_some_and(|remote| remote.entry_type == local.entry_type && remote.size == local.size) {
+				local.crc = Some(checksum_file(Crc64Nvme, &src.join(rel).to_string_lossy(), None)?);
+			}
+		}
+	}
+	let actions = plan_sync_to_server(&local_entries, &remote_entries, sync_options);
+
+	if sync_options.dry_run {
+		return Ok(actions);
+	}
+
+	//execute
+	let nactions = actions.len();
+	for (iaction, action) in actions.iter().enumerate() {
+		info!("sync {}/{}: {} {}", iaction+1, nactions, action.action.as_str(), action.path);
+		match action.action {
+			SyncActionType::Upload => {
+				upload_file_to_server(host, port, src.join(&action.path), parent_of(&dest, &action.path), true, options)?;
+			}
+			SyncActionType::Update => {
+				upload_file_to_server(host, port, src.join(&action.path), parent_of(&dest, &action.path), false, options)?;
+			}
+			SyncActionType::MakeDir => {
+				make_dir_on_server(host, port, dest.join(&action.path), options)?;
+			}
+			SyncActionType::Delete => {
+				delete_path_from_server(host, port, dest.join(&action.path), true, true, false, options)?;
+			}
+			SyncActionType::Download | SyncActionType::DeleteLocal | SyncActionType::Conflict => {
+				unreachable!("not planned by a one-way sync");
+			}
+		}
+	}
+
+	Ok(actions)
+}
+
+#[derive(Clone, Debug)]
+pub struct SyncStateEntry {
+    pub size: u64,
+    pub local_mtime: u64,
+    pub remote_mtime: u64,
+}
+
+fn read_sync_state(state_path:&Path, remote_id:&str) -> Result<BTreeMap<String, SyncStateEntry>, Box<dyn Error>> {
+	//text file: a header line "tfcsync1<TAB>remote_id", then "size<TAB>local_mtime<TAB>remote_mtime<TAB>path" per synced file
+	let mut state: BTreeMap<String, SyncStateEntry> = BTreeMap::new();
+	if !state_path.exists() {
+		return Ok(state);
+	}
+	let text = fs::read_to_string(state_path)?;
+	let mut lines = text.lines();
+	if lines.next() != Some(&format!("tfcsync1\t{}", remote_id)) {
+		warn!("Sync state in {} is for a different server directory, starting fresh", state_path.to_string_lossy());
+		return Ok(state);
+	}
+	for line in lines {
+		let fields: Vec<&str> = line.splitn(4, '\t').collect();
+		if fields.len() != 4 {
+			Err(format!("Invalid line in sync state {}: {}", state_path.to_string_lossy(), line))?
+		}
+		state.insert(fields[3].to_string(), SyncStateEntry {
+			size: fields[0].parse()?,
+			local_mtime: fields[1].parse()?,
+			remote_mtime: fields[2].parse()?,
+		});
+	}
+	Ok(state)
+}
+
+fn write_sync_state(state_path:&Path, remote_id:&str, state:&BTreeMap<String, SyncStateEntry>) -> Result<(), Box<dyn Error>> {
+	let tmp_path = state_path.with_file_name(SYNC_STATE_TMP_FILENAME);
+	{
+		let mut file = fs::File::create(&tmp_path)?;
+		writeln!(file, "tfcsync1\t{}", remote_id)?;
+		for (path, entry) in state {
+			if path.contains('\n') {
+				warn!("Not recording sync state for path containing a newline: {:?}", path);
+				continue;
+			}
+			writeln!(file, "{}\t{}\t{}\t{}", entry.size, entry.local_mtime, entry.remote_mtime, path)?;
+		}
+		file.sync_all()?;
+	}
+	fs::rename(&tmp_path, state_path)?;
+	Ok(())
+}
+
+fn conflict_name(rel:&str) -> String {
+	//report.txt -> report.conflict-1767225600.txt
+	let now = systemtime_to_unixtimestamp(SystemTime::now());
+	let (dir, name) = match rel.rsplit_once('/') {
+		Some((dir, name)) => (format!("{}/", dir), name),
+		None => (String::new(), rel),
+	};
+	match name.rsplit_once('.') {
+		Some((stem, ext)) if !stem.is_empty() => format!("{}{}.conflict-{}.{}", dir, stem, now, ext),
+		_ => format!("{}{}.conflict-{}", dir, name, now),
+	}
+}
+
+type FileListings = (BTreeMap<String, ListEntry>, BTreeMap<String, ListEntry>); //local and server files, by relative path
+
+fn list_files(host:&str, port:u16, local:&Path, remote:&Path, options:&TransferOptions) -> Result<FileListings, Box<dyn Error>> {
+	//files only, directories follow from the files in them
+	let mut local_files: BTreeMap<String, ListEntry> = BTreeMap::new();
+	list_local_dir(local, "", &mut local_files)?;
+	local_files.retain(|_, entry| EntryType::from_u8(entry.entry_type) == Some(EntryType::File));
+	let mut remote_files: BTreeMap<String, ListEntry> = BTreeMap::new();
+	if stat_path_on_server(host, port, remote.to_path_buf(), false, options)?.exists {
+		for entry in list_path_on_server(host, port, remote.to_path_buf(), true, None, false, options)? {
+			if EntryType::from_u8(entry.entry_type) == Some(EntryType::File) && entry.path != SYNC_STATE_FILENAME && entry.path != SYNC_STATE_TMP_FILENAME {
+				remote_files.insert(entry.path.clone(), entry);
+			}
+		}
+	}
+	Ok((local_files, remote_files))
+}
+
+fn two_way_action(local_file:Option<&ListEntry>, remote_file:Option<&ListEntry>, state_entry:Option<&SyncStateEntry>) -> Option<(SyncActionType, u64)> {
+	//a side has changed a file if its size/mtime differ from the recorded state, or it appeared/disappeared
+	let is_local_changed = match (local_file, state_entry) {
+		(Some(local_file), Some(state_entry)) => local_file.size != state_entry.size || local_file.mtime != state_entry.local_mtime,
+		(None, None) => false,
+		_ => true,
+	};
+	let is_remote_changed = match (remote_file, state_entry) {
+		(Some(remote_file), Some(state_entry)) => remote_file.size != state_entry.size || remote_file.mtime != state_entry.remote_mtime,
+		(None, None) => false,
+		_ => true,
+	};
+	match (is_local_changed, is_remote_changed, local_file, remote_file) {
+		(false, false, _, _) => None,
+		(true, false, Some(local_file), _) => Some((SyncActionType::Upload, local_file.size)),
+		(true, false, None, Some(remote_file)) => Some((SyncActionType::Delete, remote_file.size)),
+		(false, true, _, Some(remote_file)) => Some((SyncActionType::Download, remote_file.size)),
+		(false, true, Some(local_file), None) => Some((SyncActionType::DeleteLocal, local_file.size)),
+		//changed on both sides. a change wins over a deletion
+		(true, true, Some(local_file), None) => Some((SyncActionType::Upload, local_file.size)),
+		(true, true, None, Some(remote_file)) => Some((SyncActionType::Download, remote_file.size)),
+		//the caller checks whether both changed to the same content
+		(true, true, Some(local_file), Some(_)) => Some((SyncActionType::Conflict, local_file.size)),
+		_ => None,
+	}
+}
+
+pub fn sync_dir_two_way(host:&str, port:u16, local:PathBuf, remote:PathBuf, dry_run:bool, options:&TransferOptions) -> Result<Vec<SyncAction>, Box<dyn Error>> {
+/*
+Two-way Sync:
+1. list the local and server trees, and read the state recorded at the end of the last sync (SYNC_STATE_FILENAME)
+2. a side has changed a file if its size/mtime differ from the recorded state (or the file appeared/disappeared)
+   changed on one side only: copy it (or the deletion) to the other side
+   changed on both sides: if the crcs match just record it, otherwise it is a conflict. the local copy is renamed
+   to a conflict name, the server version downloaded, and the conflict copy uploaded so both sides keep both versions
+3. relist and record the new state
+*/
+
+	if !local.is_dir() {
+		Err(format!("Local path is not a directory: {}", local.to_string_lossy()))?
+	}
+	let remote_id = format!("{}:{}/{}", host, port, remote.to_string_lossy());
+	let state_path = local.join(SYNC_STATE_FILENAME);
+	let mut state = read_sync_state(&state_path, &remote_id)?;
+	let (local_files, remote_files) = list_files(host, port, &local, &remote, options)?;
+
+	//plan
+	let mut paths: Vec<&String> = local_files.keys().chain(remote_files.keys()).chain(state.keys()).collect();
+	paths.sort();
+	paths.dedup();
+	let mut actions: Vec<SyncAction> = Vec::new();
+	for rel in paths {
+		let local_file = local_files.get(rel);
+		let remote_file = remote_files.get(rel);
+		let state_entry = state.get(rel);
+		let action = match two_way_action(local_file, remote_file, state_entry) {
+			Some((SyncActionType::Conflict, size)) => {
+				//both changed, but maybe to the same content
+				let is_same = local_file.zip(remote_file).is_some_and(|(local_file, remote_file)| local_file.size == remote_file.size) && {
+					let local_crc = checksum_file(Crc64Nvme, &local.join(rel).to_string_lossy(), None)?;
+					stat_path_on_server(host, port, remote.join(rel), true, options)?.crc == Some(local_crc)
+				};
+				if is_same {None} else {Some((SyncActionType::Conflict, size))}
+			}
+			action => action,
+		};
+		if let Some((action, size)) = action {
+			actions.push(SyncAction { action: action, path: rel.clone(), size: size });
+		}
+	}
+
+	if dry_run {
+		return Ok(actions);
+	}
+
+	//execute
+	let nactions = actions.len();
+	for (iaction, action) in actions.iter().enumerate() {
+		info!("sync {}/{}: {} {}", iaction+1, nactions, action.action.as_str(), action.path);
+		match action.action {
+			SyncActionType::Upload | SyncActionType::Update => {
+				upload_file_to_server(host, port, local.join(&action.path), parent_of(&remote, &action.path), false, options)?;
+			}
+			SyncActionType::Download => {
+				download_file_from_server(host, port, remote.join(&action.path), parent_of(&local, &action.path), false, options)?;
+			}
+			SyncActionType::Delete => {
+				delete_path_from_server(host, port, remote.join(&action.path), false, false, false, options)?;
+			}
+			SyncActionType::DeleteLocal => {
+				fs::remove_file(local.join(&action.path))?;
+			}
+			SyncActionType::Conflict => {
+				let conflict_path = conflict_name(&action.path);
+				warn!("Conflict: {} changed on both sides, local version kept as {}", action.path, conflict_path);
+				fs::rename(local.join(&action.path), local.join(&conflict_path))?;
+				download_file_from_server(host, port, remote.join(&action.path), parent_of(&local, &action.path), false, options)?;
+				upload_file_to_server(host, port, local.join(&conflict_path), parent_of(&remote, &conflict_path), false, options)?;
+			}
+			SyncActionType::MakeDir => {
+				make_dir_on_server(host, port, remote.join(&action.path), options)?;
+			}
+		}
+	}
+
+	//record what both sides now hold. a file whose sizes differ changed during the sync, so its old state is kept
+	let (local_files, remote_files) = list_files(host, port, &local, &remote, options)?;
+	state.retain(|rel, _| local_files.contains_key(rel) && remote_files.contains_key(rel));
+	for (rel, local_file) in &local_files {
+		if let Some(remote_file) = remote_files.get(rel) && local_file.size == remote_file.size {
+			state.insert(rel.clone(), SyncStateEntry {
+				size: local_file.size,
+				local_mtime: local_file.mtime,
+				remote_mtime: remote_file.mtime,
+			});
+		}
+	}
+	write_sync_state(&state_path, &remote_id, &state)?;
+
+	Ok(actions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, entry_type: EntryType, size: u64, mtime: u64, crc: Option<u64>) -> (String, ListEntry) {
+        (path.to_string(), ListEntry {
+            path: path.to_string(),
+            entry_type: entry_type.to_u8(),
+            size: size,
+            mtime: mtime,
+            crc: crc,
+        })
+    }
+
+    fn planned(actions: &[SyncAction]) -> Vec<(SyncActionType, &str)> {
+        actions.iter().map(|action| (action.action, action.path.as_str())).collect()
+    }
+
+    #[test]
+    fn test_plan_to_server() {
+        let local_entries = BTreeMap::from([
+            entry("dir", EntryType::Dir, 0, 1, None),
+            entry("dir/new.txt", EntryType::File, 5, 1, None),
+            entry("empty", EntryType::Dir, 0, 1, None),
+            entry("resized.txt", EntryType::File, 6, 1, None),
+            entry("same.txt", EntryType::File, 5, 1, None),
+            entry("touched.txt", EntryType::File, 5, 2, None),
+            entry("was_dir", EntryType::File, 5, 1, None),
+        ]);
+        let remote_entries = BTreeMap::from([
+            entry("extra", EntryType::Dir, 0, 1, None),
+            entry("extra/inside.txt", EntryType::File, 5, 1, None),
+            entry("resized.txt", EntryType::File, 5, 1, None),
+            entry("same.txt", EntryType::File, 5, 1, None),
+            entry("touched.txt", EntryType::File, 5, 1, None),
+            entry("was_dir", EntryType::Dir, 0, 1, None),
+        ]);
+        let actions = plan_sync_to_server(&local_entries, &remote_entries, SyncOptions::default());
+        assert_eq!(planned(&actions), vec![
+            (SyncActionType::Upload, "dir/new.txt"),
+            (SyncActionType::MakeDir, "empty"),
+            (SyncActionType::Update, "resized.txt"),
+            (SyncActionType::Update, "touched.txt"),
+        ]);
+        //a deleted directory takes what is in it
+        let sync_options = SyncOptions {
+            delete_extras: true,
+            ..Default::default()
+        };
+        let actions = plan_sync_to_server(&local_entries, &remote_entries, sync_options);
+        assert_eq!(planned(&actions)[4..], [(SyncActionType::Delete, "extra")]);
+    }
+
+    #[test]
+    fn test_plan_to_server_by_hash() {
+        //with use_hash an mtime difference alone is not a change, a crc difference is
+        let local_entries = BTreeMap::from([
+            entry("a.txt", EntryType::File, 5, 2, Some(10)),
+            entry("b.txt", EntryType::File, 5, 1, Some(11)),
+            entry("c.txt", EntryType::File, 5, 1, Some(12)),
+        ]);
+        let remote_entries = BTreeMap::from([
+            entry("a.txt", EntryType::File, 5, 1, Some(10)),
+            entry("b.txt", EntryType::File, 5, 1, Some(99)),
+            entry("c.txt", EntryType::File, 5, 1, None),
+        ]);
+        let sync_options = SyncOptions {
+            use_hash: true,
+            ..Default::default()
+        };
+        let actions = plan_sync_to_server(&local_entries, &remote_entries, sync_options);
+        assert_eq!(planned(&actions), vec![(SyncActionType::Update, "b.txt"), (SyncActionType::Update, "c.txt")]);
+    }
+
+    fn state(size: u64, local_mtime: u64, remote_mtime: u64) -> SyncStateEntry {
+        SyncStateEntry { size: size, local_mtime: local_mtime, remote_mtime: remote_mtime }
+    }
+
+    #[test]
+    fn test_two_way_action() {
+        let (_, file) = entry("a.txt", EntryType::File, 5, 1, None);
+        let (_, newer) = entry("a.txt", EntryType::File, 5, 2, None);
+        let (_, resized) = entry("a.txt", EntryType::File, 7, 1, None);
+        let synced = state(5, 1, 1);
+        //unchanged, or gone from both sides
+        assert_eq!(two_way_action(Some(&file), Some(&file), Some(&synced)), None);
+        assert_eq!(two_way_action(None, None, Some(&synced)), None);
+        //changed on one side only
+        assert_eq!(two_way_action(Some(&newer), Some(&file), Some(&synced)), Some((SyncActionType::Upload, 5)));
+        assert_eq!(two_way_action(Some(&file), Some(&resized), Some(&synced)), Some((SyncActionType::Download, 7)));
+        assert_eq!(two_way_action(Some(&file), None, None), Some((SyncActionType::Upload, 5)));
+        assert_eq!(two_way_action(None, Some(&file), None), Some((SyncActionType::Download, 5)));
+        //deleted on one side only
+        assert_eq!(two_way_action(None, Some(&file), Some(&synced)), Some((SyncActionType::Delete, 5)));
+        assert_eq!(two_way_action(Some(&file), None, Some(&synced)), Some((SyncActionType::DeleteLocal, 5)));
+        //a change wins over a deletion
+        assert_eq!(two_way_action(Some(&newer), None, Some(&synced)), Some((SyncActionType::Upload, 5)));
+        assert_eq!(two_way_action(None, Some(&resized), Some(&synced)), Some((SyncActionType::Download, 7)));
+        //changed on both sides, or new on both sides
+        assert_eq!(two_way_action(Some(&newer), Some(&resized), Some(&synced)), Some((SyncActionType::Conflict, 5)));
+        assert_eq!(two_way_action(Some(&file), Some(&file), None), Some((SyncActionType::Conflict, 5)));
+    }
+
+    #[test]
+    fn test_conflict_name() {
+        let name = conflict_name("dir/report.txt");
+        let stamp = name.strip_prefix("dir/report.conflict-").and_then(|rest| rest.strip_suffix(".txt"));
+        assert!(stamp.is_some_and(|stamp| stamp.parse::<u64>().is_ok()), "{}", name);
+        let name = conflict_name(".profile");
+        assert!(name.starts_with(".profile.conflict-"), "{}", name);
+        let name = conflict_name("README");
+        assert!(name.starts_with("README.conflict-") && !name.contains("/"), "{}", name);
+    }
+}
@@ -0,0 +1,22 @@
+//helpers shared by the unit tests of several modules
+use std::path::PathBuf;
+
+pub fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+    //the same pseudo-random bytes for the same seed
+    let mut state = seed;
+    (0..len).map(|_| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 33) as u8
+    }).collect()
+}
+
+pub fn temp_path(prefix: &str, suffix: &str) -> PathBuf {
+    //a path in the temp directory no other test uses
+    std::env::temp_dir().join(format!("{}{}{}", prefix, uuid::Uuid::new_v4(), suffix))
+}
+
+pub fn assert_round_trip(src: &[u8], written: Result<u64, std::io::Error>, rebuilt: &[u8], what: &str) {
+    //src was sent through what and rebuilt, which has to give src back and count all of it
+    assert_eq!(written.unwrap(), src.len() as u64, "{} round trip wrote the wrong length", what);
+    assert!(rebuilt == src, "{} round trip changed the bytes", what);
+}
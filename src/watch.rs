@@ -0,0 +1,200 @@
+use helper_lib::datetime::systemtime_to_unixtimestamp;
+use log::*;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+use crate::sync::{SyncOptions, sync_dir_to_server};
+use crate::{ChangeType, EntryType, TransferOptions, delete_path_from_server, download_dest, download_file_from_server, is_partial_path, is_relative_path, subscribe_to_server, upload_file_to_server};
+
+pub const DEFAULT_SETTLE: Duration = Duration::from_secs(2);
+
+pub struct PendingPaths {
+    settle: Duration,
+    pending: HashMap<PathBuf, (Instant, Option<(u64, SystemTime)>)>,
+}
+impl PendingPaths {
+    //debounces change events: a path is settled once it has had no events for the settle time and its size/mtime stopped changing
+    pub fn new(settle: Duration) -> PendingPaths {
+        PendingPaths {
+            settle: settle,
+            pending: HashMap::new(),
+        }
+    }
+
+    fn snapshot(path: &Path) -> Option<(u64, SystemTime)> {
+        fs::metadata(path).ok().map(|metadata| (metadata.len(), metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH)))
+    }
+
+    pub fn touch(&mut self, path: PathBuf) {
+        let snapshot = PendingPaths::snapshot(&path);
+        self.pending.insert(path, (Instant::now(), snapshot));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn take_settled(&mut self) -> Vec<PathBuf> {
+        let mut settled: Vec<PathBuf> = Vec::new();
+        let now = Instant::now();
+        for (path, (last_event, snapshot)) in self.pending.iter_mut() {
+            if now.duration_since(*last_event) < self.settle {
+                continue;
+            }
+            let current = PendingPaths::snapshot(path);
+            if current != *snapshot {
+                //still being written without raising events, wait another settle period
+                *last_event = now;
+                *snapshot = current;
+                continue;
+            }
+            settled.push(path.clone());
+        }
+        for path in &settled {
+            self.pending.remove(path);
+        }
+        settled.sort();
+        settled
+    }
+}
+
+fn push_change(host:&str, port:u16, path:&Path, src:&Path, remote_root:&Path, delete_remote:bool, options:&TransferOptions) -> Result<(), Box<dyn Error>> {
+	let rel = path.strip_prefix(src)?;
+	let remote_path = remote_root.join(rel);
+	let remote_dir = remote_path.parent().expect("no parent for remote path").to_path_buf();
+	if path.is_file() {
+		info!("watch: uploading {}", path.to_string_lossy());
+		upload_file_to_server(host, port, path.to_path_buf(), remote_dir, false, options)?;
+	} else if path.is_dir() {
+		//a directory moved in raises no events for its contents
+		info!("watch: syncing directory {}", path.to_string_lossy());
+		sync_dir_to_server(host, port, path.to_path_buf(), remote_path, SyncOptions::default(), options)?;
+	} else if !path.exists() && delete_remote {
+		info!("watch: deleting {}", remote_path.to_string_lossy());
+		delete_path_from_server(host, port, remote_path, true, true, false, options)?;
+	}
+	Ok(())
+}
+
+pub fn watch_and_upload(host:&str, port:u16, src:PathBuf, dest:PathBuf, settle:Duration, delete_remote:bool, options:&TransferOptions) -> Result<(), Box<dyn Error>> {
+/*
+Watch Upload:
+1. upload src (a file, or the whole tree of a directory) to dest as a normal upload would place it
+2. watch src and, once a changed path has settled, upload it again (or delete it on the server when delete_remote)
+Runs until the watcher fails.
+*/
+
+	if !src.exists() {
+		Err(format!("Source path does not exist on client: {}", src.to_string_lossy()))?
+	}
+	let src = fs::canonicalize(&src)?;
+	let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
+	let mut watcher = notify::recommended_watcher(tx)?;
+	let (watch_root, remote_root) = if src.is_dir() {
+		let remote_root = dest.join(src.file_name().expect("no filename in src"));
+		sync_dir_to_server(host, port, src.clone(), remote_root.clone(), SyncOptions::default(), options)?;
+		watcher.watch(&src, RecursiveMode::Recursive)?;
+		(src.clone(), remote_root)
+	} else {
+		upload_file_to_server(host, port, src.clone(), dest.clone(), true, options)?;
+		let parent_dir = src.parent().expect("no parent for src").to_path_buf();
+		watcher.watch(&parent_dir, RecursiveMode::NonRecursive)?;
+		(parent_dir, dest.clone())
+	};
+	info!("Watching {} for changes...", src.to_string_lossy());
+
+	let mut pending = PendingPaths::new(settle);
+	loop {
+		match rx.recv_timeout(Duration::from_millis(250)) {
+			Ok(Ok(event)) => {
+				if matches!(event.kind, EventKind::Access(_)) {
+					continue;
+				}
+				for path in event.paths {
+					//watching a single file watches its directory, ignore the neighbours. downloads in progress are not pushed
+					if path.starts_with(&src) && path != watch_root && !is_partial_path(&path) {
+						pending.touch(path);
+					}
+				}
+			}
+			Ok(Err(e)) => {
+				error!("watch error: {}", e);
+			}
+			Err(mpsc::RecvTimeoutError::Timeout) => {}
+			Err(mpsc::RecvTimeoutError::Disconnected) => {
+				Err("File watcher stopped")?
+			}
+		}
+		if pending.is_empty() {
+			continue;
+		}
+		for path in pending.take_settled() {
+			//a failed push is logged and picked up again by the next change
+			if let Err(e) = push_change(host, port, &path, &watch_root, &remote_root, delete_remote, options) {
+				error!("watch: could not push {}: {}", path.to_string_lossy(), e);
+			}
+		}
+	}
+}
+
+pub fn follow_server_dir(host:&str, port:u16, src:PathBuf, dest:PathBuf, settle:Duration, delete_local:bool, options:&TransferOptions) -> Result<(), Box<dyn Error>> {
+/*
+Follow:
+1. subscribe to changes below the server directory src
+2. once subscribed, download the whole directory into dest as a normal download would place it
+3. download each created/modified file as its event arrives (and delete locally with delete_local)
+Runs until the server closes the subscription.
+*/
+
+	let local_root = download_dest(&src, &dest);
+	subscribe_to_server(host, port, src.clone(), settle, options, |event| {
+		let change_type = ChangeType::from_u8(event.change_type);
+		if change_type != Some(ChangeType::Subscribed) && !is_relative_path(Path::new(&event.path)) {
+			Err(format!("Server sent an event for a path outside of {}: {}", src.to_string_lossy(), event.path))?
+		}
+		let local_path = local_root.join(&event.path);
+		match change_type {
+			Some(ChangeType::Subscribed) => {
+				info!("Subscribed to {}, downloading current contents...", src.to_string_lossy());
+				download_file_from_server(host, port, src.clone(), dest.clone(), true, options)?;
+			}
+			Some(ChangeType::Created) | Some(ChangeType::Modified) => {
+				match EntryType::from_u8(event.entry_type) {
+					Some(EntryType::Dir) => {
+						fs::create_dir_all(&local_path)?;
+					}
+					Some(EntryType::File) => {
+						let is_current = fs::metadata(&local_path)
+							.map(|metadata| metadata.len() == event.size && systemtime_to_unixtimestamp(metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH)) == event.mtime)
+							.unwrap_or(false);
+						if !is_current {
+							info!("follow: {} {}", ChangeType::from_u8(event.change_type).map(|change_type| change_type.as_str()).unwrap_or(""), event.path);
+							let local_dir = local_path.parent().expect("no parent for local path").to_path_buf();
+							//the file may already be gone again, log and keep following
+							if let Err(e) = download_file_from_server(host, port, src.join(&event.path), local_dir, false, options) {
+								error!("follow: could not download {}: {}", event.path, e);
+							}
+						}
+					}
+					_ => {}
+				}
+			}
+			Some(ChangeType::Deleted) if delete_local => {
+				if let Ok(metadata) = fs::symlink_metadata(&local_path) {
+					info!("follow: deleted {}", event.path);
+					if metadata.is_dir() {
+						fs::remove_dir_all(&local_path)?;
+					} else {
+						fs::remove_file(&local_path)?;
+					}
+				}
+			}
+			_ => {}
+		}
+		Ok(())
+	})
+}